@@ -0,0 +1,196 @@
+//! On-disk cache of file contents known to be formatted.
+//!
+//! Entries are SHA-256 digests of file contents, most recently used first.
+//! Each combination of nufmt version and resolved config gets its own cache
+//! file, so changing either one starts from an empty cache instead of
+//! trusting stale results.
+
+use std::{
+    collections::HashSet,
+    env,
+    ffi::OsStr,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use nufmt_core::Config;
+use sha2::{Digest, Sha256};
+
+/// Most entries a cache file keeps, dropping the least recently used.
+///
+/// Entries used in the run that saves the file are always kept, so a tree
+/// with more formatted files than this still hits on every one of them.
+pub const MAX_ENTRIES: usize = 10_000;
+
+/// Cache files left unwritten this long belong to a config or nufmt version
+/// that is no longer used, and are removed.
+const STALE_AFTER: Duration = Duration::from_hours(30 * 24);
+
+/// Set of content hashes for files that are already formatted.
+pub struct Cache {
+    /// Location of the cache file on disk.
+    path: PathBuf,
+    /// Hex-encoded content digests read from disk, most recently used first.
+    loaded: Vec<String>,
+    /// The same digests, for lookups.
+    known: HashSet<String>,
+    /// Digests of content found or recorded as formatted since the cache was loaded.
+    used: Mutex<HashSet<String>>,
+}
+
+impl Cache {
+    /// Open the cache for this nufmt version and config in the user cache directory.
+    ///
+    /// Returns `None` if no cache directory can be determined.
+    pub fn open(config: &Config) -> Option<Self> {
+        cache_dir().map(|dir| Self::load(&dir, config))
+    }
+
+    /// Load the cache for this nufmt version and config from `dir`.
+    ///
+    /// A missing or unreadable cache file yields an empty cache.
+    pub fn load(dir: &Path, config: &Config) -> Self {
+        let path = dir.join(fingerprint(config));
+        let loaded = read_entries(&path);
+
+        Self {
+            path,
+            known: loaded.iter().cloned().collect(),
+            loaded,
+            used: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Check whether `content` is known to be formatted.
+    pub fn contains(&self, content: &str) -> bool {
+        let key = digest(content.as_bytes());
+        let Ok(mut used) = self.used.lock() else {
+            return false;
+        };
+        if used.contains(&key) {
+            return true;
+        }
+        let known = self.known.contains(&key);
+        if known {
+            used.insert(key);
+        }
+        known
+    }
+
+    /// Record `content` as formatted.
+    pub fn insert(&self, content: &str) {
+        let key = digest(content.as_bytes());
+        if let Ok(mut used) = self.used.lock() {
+            used.insert(key);
+        }
+    }
+
+    /// Write the cache back to disk if it was used, and remove stale cache
+    /// files.
+    ///
+    /// Entries used in this run go first, all of them, then the ones loaded,
+    /// then any that other runs wrote in the meantime, up to [`MAX_ENTRIES`]
+    /// in total. The file is replaced atomically, so concurrent runs never
+    /// leave it truncated.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the cache directory or file can't be written.
+    pub fn save(&self) -> io::Result<()> {
+        let mut used: Vec<String> = self
+            .used
+            .lock()
+            .map(|used| used.iter().cloned().collect())
+            .unwrap_or_default();
+        if used.is_empty() {
+            return Ok(());
+        }
+        used.sort_unstable();
+
+        let mut seen: HashSet<String> = used.iter().cloned().collect();
+        let mut keys = used;
+        for key in self.loaded.iter().cloned().chain(read_entries(&self.path)) {
+            if keys.len() >= MAX_ENTRIES {
+                break;
+            }
+            if seen.insert(key.clone()) {
+                keys.push(key);
+            }
+        }
+
+        let dir = self.path.parent().unwrap_or_else(|| Path::new("."));
+        fs::create_dir_all(dir)?;
+        let mut content = keys.join("\n");
+        content.push('\n');
+        crate::write_atomic(&self.path, &content, None)?;
+
+        remove_stale(dir);
+        Ok(())
+    }
+}
+
+/// Read the entries of a cache file, or none if it can't be read.
+fn read_entries(path: &Path) -> Vec<String> {
+    fs::read_to_string(path)
+        .map(|content| content.lines().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// Remove the cache files in `dir` that haven't been written for
+/// [`STALE_AFTER`].
+///
+/// Failures are ignored; a stale file left behind only takes up space.
+fn remove_stale(dir: &Path) {
+    let Ok(files) = fs::read_dir(dir) else {
+        return;
+    };
+    for file in files.flatten() {
+        let stale = file
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > STALE_AFTER));
+        if stale && is_cache_file(&file.file_name()) {
+            let _ = fs::remove_file(file.path());
+        }
+    }
+}
+
+/// Whether `name` is that of a cache file, a hex-encoded digest.
+fn is_cache_file(name: &OsStr) -> bool {
+    name.to_str()
+        .is_some_and(|name| name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Resolve the nufmt cache directory (`$XDG_CACHE_HOME/nufmt` or `~/.cache/nufmt`).
+fn cache_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .map(|dir| dir.join("nufmt"))
+}
+
+/// Name of the cache file for this nufmt version and config.
+///
+/// The config is serialized as TOML, the same form it's read from, so every
+/// option is named along with its value and any change to the resolved
+/// config produces a different file.
+fn fingerprint(config: &Config) -> String {
+    let config = toml::to_string(config).expect("config serializes to TOML");
+    let key = format!("{}\n{config}", env!("CARGO_PKG_VERSION"));
+    digest(key.as_bytes())
+}
+
+/// Hex-encoded SHA-256 digest.
+fn digest(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+
+    Sha256::digest(bytes)
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
@@ -1,20 +1,28 @@
+mod cache;
+
 use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
     fs,
     io::{self, IsTerminal, Read, Write},
-    path::{Path, PathBuf},
-    process::ExitCode,
+    path::{Component, Path, PathBuf},
+    process::{self, ExitCode},
     sync::atomic::{AtomicUsize, Ordering},
 };
 
 use clap::{Parser, Subcommand, ValueEnum};
 use nufmt_core::{
-    BracketSpacing, Config, FormatError, QuoteStyle, TrailingComma, debug_tokens, format_source,
+    BlockLayout, BracketSpacing, CollectionLayout, Config, DigitGrouping, FlagValueStyle,
+    FormatError, KeyQuotes, NumberCase, PipePosition, QuoteStyle, RedirectionStyle, TrailingComma,
+    UnitCase, debug_tokens, format_source,
 };
 use owo_colors::OwoColorize;
 use rayon::prelude::*;
 use similar::TextDiff;
 use thiserror::Error;
 
+use cache::Cache;
+
 /// When to use colored output.
 #[derive(Debug, Clone, Copy, Default, ValueEnum)]
 enum ColorChoice {
@@ -51,6 +59,7 @@ enum FormatResult {
 /// A code formatter for Nushell
 #[derive(Parser, Debug)]
 #[command(name = "nufmt", version, about, arg_required_else_help = true)]
+#[allow(clippy::struct_excessive_bools)] // Independent CLI flags
 struct Args {
     #[command(subcommand)]
     command: Option<Command>,
@@ -67,6 +76,30 @@ struct Args {
     #[arg(long)]
     stdin: bool,
 
+    /// Format the .nu files staged in git instead of the given patterns
+    #[arg(long, conflicts_with_all = ["patterns", "stdin", "files_from"])]
+    staged: bool,
+
+    /// Read paths to format from a file, one per line (use - for stdin)
+    #[arg(long, value_name = "FILE", conflicts_with = "stdin")]
+    files_from: Option<PathBuf>,
+
+    /// With --files-from, paths are separated by NUL characters instead of newlines
+    #[arg(short = '0', long, requires = "files_from")]
+    null: bool,
+
+    /// Don't skip files recorded as formatted in the cache, and don't update it
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Write formatted files into this directory, mirroring their paths, instead of in place
+    #[arg(long, conflicts_with_all = ["check", "stdin"])]
+    output_dir: Option<PathBuf>,
+
+    /// With --output-dir, also copy files that are already formatted
+    #[arg(long, requires = "output_dir")]
+    copy_unchanged: bool,
+
     /// Path to config file (default: .nufmt.toml in current or parent directories)
     #[arg(long, short)]
     config: Option<PathBuf>,
@@ -94,6 +127,54 @@ struct Args {
     /// Whether to add trailing commas in multiline collections
     #[arg(long, value_enum)]
     trailing_comma: Option<TrailingComma>,
+
+    /// Where to place `|` in pipelines broken across lines
+    #[arg(long, value_enum)]
+    pipe_position: Option<PipePosition>,
+
+    /// Column-align `=>` across the arms of a `match`
+    #[arg(long, value_name = "BOOL")]
+    align_match_arrows: Option<bool>,
+
+    /// Pad the cells of table literals so their columns line up
+    #[arg(long, value_name = "BOOL")]
+    align_tables: Option<bool>,
+
+    /// Pad record keys so the values of a multiline record line up
+    #[arg(long, value_name = "BOOL")]
+    align_record_values: Option<bool>,
+
+    /// How to choose between one-line and multiline layouts for lists and records
+    #[arg(long, value_enum)]
+    collection_layout: Option<CollectionLayout>,
+
+    /// When to put the body of a brace block on its own lines
+    #[arg(long, value_enum)]
+    block_layout: Option<BlockLayout>,
+
+    /// How to join a long flag to its value
+    #[arg(long, value_enum)]
+    flag_value_style: Option<FlagValueStyle>,
+
+    /// How to spell redirection operators
+    #[arg(long, value_enum)]
+    redirection_style: Option<RedirectionStyle>,
+
+    /// When to quote record keys and cell-path members
+    #[arg(long, value_enum)]
+    key_quotes: Option<KeyQuotes>,
+
+    /// How to write the letters of number literals
+    #[arg(long, value_enum)]
+    number_case: Option<NumberCase>,
+
+    /// How to write the units of filesize and duration literals
+    #[arg(long, value_enum)]
+    unit_case: Option<UnitCase>,
+
+    /// How to group the digits of long integers
+    #[arg(long, value_enum)]
+    digit_grouping: Option<DigitGrouping>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -105,6 +186,17 @@ enum Command {
         force: bool,
     },
 
+    /// Install a git pre-commit hook that checks staged files
+    InstallHook {
+        /// Format staged files and re-stage them instead of only checking
+        #[arg(long)]
+        fix: bool,
+
+        /// Overwrite an existing hook that was not installed by nufmt
+        #[arg(long)]
+        force: bool,
+    },
+
     /// Debugging commands (hidden)
     #[command(hide = true)]
     Debug {
@@ -126,6 +218,7 @@ fn main() -> ExitCode {
     if let Some(command) = args.command {
         return match command {
             Command::Init { force } => run_init(force),
+            Command::InstallHook { fix, force } => run_install_hook(fix, force),
             Command::Debug { command } => run_debug(&command),
         };
     }
@@ -151,63 +244,110 @@ fn main() -> ExitCode {
                 return ExitCode::from(2);
             }
         }
-    } else if !args.patterns.is_empty() {
-        // Expand glob patterns to file paths
-        let files = match expand_patterns(&args.patterns) {
-            Ok(f) => f,
-            Err(e) => {
-                eprintln!("error: {e}");
-                return ExitCode::from(2);
-            }
-        };
+    } else if args.staged || args.files_from.is_some() || !args.patterns.is_empty() {
+        return format_files(&args, &config);
+    }
 
-        if files.is_empty() {
-            eprintln!("error: no files matched the given patterns");
+    ExitCode::SUCCESS
+}
+
+/// Format the staged files, or the files matched by the patterns and file list.
+fn format_files(args: &Args, config: &Config) -> ExitCode {
+    // Collect staged files or expand glob patterns and file lists to file paths
+    let files = if args.staged {
+        staged_files(Path::new("."))
+    } else {
+        collect_files(args)
+    };
+    let files = match files {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("error: {e}");
             return ExitCode::from(2);
         }
+    };
 
-        let total = files.len();
-        let changed = AtomicUsize::new(0);
-        let errors = AtomicUsize::new(0);
-        let use_color = args.color.should_use_color();
-
-        // Process files and collect results for printing
-        let results: Vec<_> = files
-            .par_iter()
-            .map(|path| {
-                let result = match format_file(path, &args, &config) {
-                    Ok(true) => {
-                        changed.fetch_add(1, Ordering::Relaxed);
-                        FormatResult::Changed
-                    }
-                    Ok(false) => FormatResult::Unchanged,
-                    Err(e) => {
-                        errors.fetch_add(1, Ordering::Relaxed);
-                        FormatResult::Error(e.to_string())
-                    }
-                };
-                (path.clone(), result)
-            })
-            .collect();
+    if files.is_empty() {
+        // Nothing staged is not an error (e.g., commits that touch no .nu files)
+        if args.staged {
+            return ExitCode::SUCCESS;
+        }
+        eprintln!("error: no files matched the given patterns");
+        return ExitCode::from(2);
+    }
 
-        // Print results for each file
-        for (path, result) in &results {
-            print_file_result(path, result, &args, use_color);
+    // Formatting a file with unstaged changes in place and re-staging it
+    // would stage those changes too
+    let unstaged = if args.staged && !args.check {
+        match unstaged_files(Path::new(".")) {
+            Ok(unstaged) => unstaged,
+            Err(e) => {
+                eprintln!("error: {e}");
+                return ExitCode::from(2);
+            }
         }
+    } else {
+        HashSet::new()
+    };
 
-        // Print summary
-        let changed_count = changed.load(Ordering::Relaxed);
-        let error_count = errors.load(Ordering::Relaxed);
-        print_summary(&args, total, changed_count, error_count, use_color);
+    let cache = if args.no_cache {
+        None
+    } else {
+        Cache::open(config)
+    };
+    let root = std::env::current_dir().unwrap_or_default();
+
+    let total = files.len();
+    let changed = AtomicUsize::new(0);
+    let errors = AtomicUsize::new(0);
+    let use_color = args.color.should_use_color();
+
+    // Process files and collect results for printing
+    let results: Vec<_> = files
+        .par_iter()
+        .map(|path| {
+            let outcome = if unstaged.contains(path) {
+                Err(Error::PartiallyStaged)
+            } else {
+                format_file(path, args, config, cache.as_ref(), &root)
+            };
+            let result = match outcome {
+                Ok(true) => {
+                    changed.fetch_add(1, Ordering::Relaxed);
+                    FormatResult::Changed
+                }
+                Ok(false) => FormatResult::Unchanged,
+                Err(e) => {
+                    errors.fetch_add(1, Ordering::Relaxed);
+                    FormatResult::Error(e.to_string())
+                }
+            };
+            (path.clone(), result)
+        })
+        .collect();
+
+    // Print results for each file
+    for (path, result) in &results {
+        print_file_result(path, result, args, use_color);
+    }
 
-        if error_count > 0 {
-            return ExitCode::from(2);
-        }
-        if args.check && changed_count > 0 {
-            return ExitCode::from(1);
-        }
+    if let Some(cache) = &cache
+        && let Err(e) = cache.save()
+    {
+        eprintln!("warning: failed to write cache: {e}");
     }
 
+    // Print summary
+    let changed_count = changed.load(Ordering::Relaxed);
+    let error_count = errors.load(Ordering::Relaxed);
+    print_summary(args, total, changed_count, error_count, use_color);
+
+    if error_count > 0 {
+        return ExitCode::from(2);
+    }
+    if args.check && changed_count > 0 {
+        return ExitCode::from(1);
+    }
     ExitCode::SUCCESS
 }
 
@@ -360,6 +500,150 @@ fn run_init(force: bool) -> ExitCode {
     ExitCode::SUCCESS
 }
 
+/// First line after the shebang of every hook written by `install-hook`.
+///
+/// Used to recognize our own hooks so they can be replaced without `--force`.
+const HOOK_MARKER: &str = "# Installed by `nufmt install-hook`.";
+
+/// Build the pre-commit hook script.
+///
+/// In fix mode, staged files are formatted in place and added back to the index.
+/// `nufmt --staged` refuses files with unstaged changes, so only files staged
+/// whole are re-added.
+fn hook_script(fix: bool) -> String {
+    let body = if fix {
+        "nufmt --staged || exit 1\n\
+         git diff --cached --name-only -z --diff-filter=ACMR -- '*.nu' | xargs -0 git add --\n"
+    } else {
+        "exec nufmt --check --staged\n"
+    };
+    format!("#!/bin/sh\n{HOOK_MARKER}\n{body}")
+}
+
+/// Install a git pre-commit hook into the repository's hooks directory.
+fn run_install_hook(fix: bool, force: bool) -> ExitCode {
+    let hooks_dir = match git_hooks_dir(Path::new(".")) {
+        Ok(dir) => dir,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::from(2);
+        }
+    };
+    let hook_path = hooks_dir.join("pre-commit");
+
+    match install_hook(&hook_path, fix, force) {
+        Ok(()) => {
+            eprintln!("Installed pre-commit hook at {}", hook_path.display());
+            ExitCode::SUCCESS
+        }
+        Err(e @ Error::HookExists(_)) => {
+            eprintln!("error: {e}");
+            ExitCode::from(1)
+        }
+        Err(e) => {
+            eprintln!("error: failed to write {}: {e}", hook_path.display());
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// Write the pre-commit hook to `path` and make it executable.
+///
+/// Refuses to replace a hook that wasn't written by nufmt unless `force` is
+/// set. A hook that can't be read counts as someone else's.
+fn install_hook(path: &Path, fix: bool, force: bool) -> Result<(), Error> {
+    if !force && fs::symlink_metadata(path).is_ok() {
+        let marker = HOOK_MARKER.as_bytes();
+        let ours = fs::read(path)
+            .is_ok_and(|existing| existing.windows(marker.len()).any(|w| w == marker));
+        if !ours {
+            return Err(Error::HookExists(path.to_path_buf()));
+        }
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, hook_script(fix))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    }
+
+    Ok(())
+}
+
+/// Locate the hooks directory of the repository containing `dir`, honoring
+/// `core.hooksPath`.
+fn git_hooks_dir(dir: &Path) -> Result<PathBuf, Error> {
+    let output = git(dir, &["rev-parse", "--git-path", "hooks"])?;
+    Ok(dir.join(path_from_bytes(trim_newline(&output))?))
+}
+
+/// List the `.nu` files staged for commit in the repository containing `dir`.
+///
+/// Deleted files are excluded. Paths are resolved against the repository root.
+fn staged_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    changed_files(dir, &["--cached", "--diff-filter=ACMR"])
+}
+
+/// List the `.nu` files with changes that aren't staged, in the repository
+/// containing `dir`.
+fn unstaged_files(dir: &Path) -> Result<HashSet<PathBuf>, Error> {
+    Ok(changed_files(dir, &[])?.into_iter().collect())
+}
+
+/// List the `.nu` files reported by `git diff` with `options`, resolved
+/// against the repository root.
+fn changed_files(dir: &Path, options: &[&str]) -> Result<Vec<PathBuf>, Error> {
+    let toplevel = path_from_bytes(trim_newline(&git(dir, &["rev-parse", "--show-toplevel"])?))?;
+    let mut args = vec!["diff", "--name-only", "-z"];
+    args.extend(options);
+    args.extend(["--", "*.nu"]);
+    let output = git(dir, &args)?;
+
+    output
+        .split(|&b| b == b'\0')
+        .filter(|name| !name.is_empty())
+        .map(|name| Ok(toplevel.join(path_from_bytes(name)?)))
+        .collect()
+}
+
+/// Read the staged contents of `path`, which may differ from the working tree.
+fn staged_source(path: &Path) -> Result<String, Error> {
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let mut object = OsString::from(":./");
+    object.push(name);
+    let contents = git(dir, &[OsStr::new("show"), &object])?;
+    String::from_utf8(contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+}
+
+/// Run a git command in `dir` and return its stdout as bytes.
+fn git<S: AsRef<OsStr>>(dir: &Path, args: &[S]) -> Result<Vec<u8>, Error> {
+    let output = process::Command::new("git")
+        .current_dir(dir)
+        .args(args)
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Git(stderr.trim().to_string()));
+    }
+    Ok(output.stdout)
+}
+
+/// Strip the line break git ends single-line output with.
+fn trim_newline(output: &[u8]) -> &[u8] {
+    output.strip_suffix(b"\n").unwrap_or(output)
+}
+
 /// Load configuration from file or use defaults, then apply CLI overrides.
 fn load_config(args: &Args) -> Result<Config, Error> {
     // Load base config from file or defaults
@@ -387,6 +671,42 @@ fn load_config(args: &Args) -> Result<Config, Error> {
     if let Some(trailing_comma) = args.trailing_comma {
         config.trailing_comma = trailing_comma;
     }
+    if let Some(pipe_position) = args.pipe_position {
+        config.pipe_position = pipe_position;
+    }
+    if let Some(align_match_arrows) = args.align_match_arrows {
+        config.align_match_arrows = align_match_arrows;
+    }
+    if let Some(align_tables) = args.align_tables {
+        config.align_tables = align_tables;
+    }
+    if let Some(align_record_values) = args.align_record_values {
+        config.align_record_values = align_record_values;
+    }
+    if let Some(collection_layout) = args.collection_layout {
+        config.collection_layout = collection_layout;
+    }
+    if let Some(block_layout) = args.block_layout {
+        config.block_layout = block_layout;
+    }
+    if let Some(flag_value_style) = args.flag_value_style {
+        config.flag_value_style = flag_value_style;
+    }
+    if let Some(redirection_style) = args.redirection_style {
+        config.redirection_style = redirection_style;
+    }
+    if let Some(key_quotes) = args.key_quotes {
+        config.key_quotes = key_quotes;
+    }
+    if let Some(number_case) = args.number_case {
+        config.number_case = number_case;
+    }
+    if let Some(unit_case) = args.unit_case {
+        config.unit_case = unit_case;
+    }
+    if let Some(digit_grouping) = args.digit_grouping {
+        config.digit_grouping = digit_grouping;
+    }
 
     // Validate the final config (in case CLI args are out of range)
     config.validate().map_err(|e| Error::Config {
@@ -429,6 +749,79 @@ fn find_config_file() -> Option<PathBuf> {
     }
 }
 
+/// Collect files from the glob patterns and the `--files-from` list.
+///
+/// Files under the output directory are left out, so that the copies written
+/// by an earlier run aren't formatted again.
+fn collect_files(args: &Args) -> Result<Vec<PathBuf>, Error> {
+    let mut files = expand_patterns(&args.patterns)?;
+
+    if let Some(list) = &args.files_from {
+        for path in read_file_list(list, args.null)? {
+            push_path(path, &mut files);
+        }
+    }
+
+    if let Some(output_dir) = &args.output_dir
+        && let Ok(output_dir) = fs::canonicalize(output_dir)
+    {
+        files
+            .retain(|path| !fs::canonicalize(path).is_ok_and(|path| path.starts_with(&output_dir)));
+    }
+
+    Ok(files)
+}
+
+/// Read a list of paths from a file, or from stdin if the path is `-`.
+///
+/// Entries are separated by newlines, or by NUL characters when
+/// `null_separated` is set (for `find -print0`). Empty entries are ignored.
+/// The list is read as bytes, so paths that aren't valid UTF-8 survive on
+/// Unix.
+fn read_file_list(list: &Path, null_separated: bool) -> Result<Vec<PathBuf>, Error> {
+    let content = if list == Path::new("-") {
+        let mut content = Vec::new();
+        io::stdin().read_to_end(&mut content)?;
+        content
+    } else {
+        fs::read(list)?
+    };
+
+    let entries: Vec<&[u8]> = if null_separated {
+        content.split(|&b| b == b'\0').collect()
+    } else {
+        content
+            .split(|&b| b == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .collect()
+    };
+
+    entries
+        .into_iter()
+        .filter(|entry| !entry.is_empty())
+        .map(path_from_bytes)
+        .collect()
+}
+
+/// Build a path from raw bytes, like a file list entry or a path printed
+/// by git.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_wraps)] // Fallible on other platforms
+fn path_from_bytes(bytes: &[u8]) -> Result<PathBuf, Error> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Ok(PathBuf::from(OsStr::from_bytes(bytes)))
+}
+
+/// Build a path from raw bytes, like a file list entry or a path printed
+/// by git.
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> Result<PathBuf, Error> {
+    let path =
+        std::str::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(PathBuf::from(path))
+}
+
 /// Expand glob patterns to file paths.
 ///
 /// If a pattern contains no glob characters, it's treated as a literal path.
@@ -450,18 +843,24 @@ fn expand_patterns(patterns: &[String]) -> Result<Vec<PathBuf>, Error> {
                 }
             }
         } else {
-            let path = PathBuf::from(pattern);
-            if path.is_dir() {
-                collect_nu_files(&path, &mut files);
-            } else {
-                files.push(path);
-            }
+            push_path(PathBuf::from(pattern), &mut files);
         }
     }
 
     Ok(files)
 }
 
+/// Add a literal path, searching directories for `.nu` files.
+///
+/// Missing files are kept so they're reported as errors when formatting.
+fn push_path(path: PathBuf, files: &mut Vec<PathBuf>) {
+    if path.is_dir() {
+        collect_nu_files(&path, files);
+    } else {
+        files.push(path);
+    }
+}
+
 /// Recursively collect all `.nu` files in a directory.
 fn collect_nu_files(dir: &Path, files: &mut Vec<PathBuf>) {
     let Ok(entries) = fs::read_dir(dir) else {
@@ -504,9 +903,35 @@ fn format_stdin(args: &Args, config: &Config) -> Result<bool, Error> {
 ///
 /// In check mode, prints a diff if changes are needed.
 /// Otherwise, writes the formatted output back to the file.
+/// Files recorded in the cache are skipped without being parsed.
+/// With `--output-dir`, the output mirrors `path` relative to `root`.
 /// Returns true if the file would change.
-fn format_file(path: &Path, args: &Args, config: &Config) -> Result<bool, Error> {
-    let source = fs::read_to_string(path)?;
+fn format_file(
+    path: &Path,
+    args: &Args,
+    config: &Config,
+    cache: Option<&Cache>,
+    root: &Path,
+) -> Result<bool, Error> {
+    // A staged file is checked as it will be committed, even if it's gone
+    // from the working tree, and check mode writes nothing that would need
+    // its metadata
+    let (source, metadata) = if args.staged && args.check {
+        (staged_source(path)?, None)
+    } else {
+        let metadata = fs::metadata(path)?;
+        (fs::read_to_string(path)?, Some(metadata))
+    };
+    if cache.is_some_and(|c| c.contains(&source)) {
+        if let Some(output_dir) = &args.output_dir
+            && let Some(metadata) = &metadata
+            && args.copy_unchanged
+        {
+            write_output(output_dir, root, path, &source, metadata.permissions())?;
+        }
+        return Ok(false);
+    }
+
     let formatted = format_source(&source, config)?;
 
     let would_change = source != formatted;
@@ -514,14 +939,103 @@ fn format_file(path: &Path, args: &Args, config: &Config) -> Result<bool, Error>
     if args.check {
         if would_change {
             print_diff(&path.display().to_string(), &source, &formatted);
+            return Ok(true);
+        }
+    } else if let Some(metadata) = &metadata {
+        if let Some(output_dir) = &args.output_dir {
+            if would_change || args.copy_unchanged {
+                write_output(output_dir, root, path, &formatted, metadata.permissions())?;
+            }
+        } else if would_change {
+            ensure_unmodified(path, metadata, &source)?;
+            write_atomic(path, &formatted, Some(metadata.permissions()))?;
         }
-    } else if would_change {
-        fs::write(path, &formatted)?;
+    }
+
+    if let Some(cache) = cache {
+        cache.insert(&formatted);
     }
 
     Ok(would_change)
 }
 
+/// Write formatted content to the mirrored location of `path` under `output_dir`.
+fn write_output(
+    output_dir: &Path,
+    root: &Path,
+    path: &Path,
+    contents: &str,
+    permissions: fs::Permissions,
+) -> Result<(), Error> {
+    let target = mirror_path(output_dir, root, path)?;
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_atomic(&target, contents, Some(permissions))?;
+    Ok(())
+}
+
+/// Map a source path to its location under the output directory.
+///
+/// Paths are mirrored relative to `root` (the current directory). Paths
+/// outside of it (absolute paths elsewhere, or relative paths climbing out
+/// with `..`) are rejected rather than written somewhere surprising.
+fn mirror_path(output_dir: &Path, root: &Path, path: &Path) -> Result<PathBuf, Error> {
+    let relative = if path.is_absolute() {
+        path.strip_prefix(root).map_err(|_| Error::Unmirrorable)?
+    } else {
+        path
+    };
+
+    let mut target = output_dir.to_path_buf();
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => target.push(part),
+            Component::CurDir => {}
+            _ => return Err(Error::Unmirrorable),
+        }
+    }
+    Ok(target)
+}
+
+/// Check that a file still matches what was read before formatting.
+///
+/// Guards against overwriting edits made by another process (e.g., an editor
+/// saving the file) while it was being formatted.
+fn ensure_unmodified(path: &Path, original: &fs::Metadata, source: &str) -> Result<(), Error> {
+    let current = fs::metadata(path)?;
+    if current.modified().ok() != original.modified().ok() || fs::read_to_string(path)? != source {
+        return Err(Error::Modified);
+    }
+    Ok(())
+}
+
+/// Replace a file's contents atomically, giving it `permissions` if set.
+///
+/// Writes to a temporary file in the same directory and renames it over the
+/// original, so an interrupted write never leaves a truncated file behind.
+/// Symlinks are resolved first, so the file they point to is replaced rather
+/// than the link itself.
+fn write_atomic(
+    path: &Path,
+    contents: &str,
+    permissions: Option<fs::Permissions>,
+) -> io::Result<()> {
+    let path = &fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+    temp.write_all(contents.as_bytes())?;
+    temp.as_file().sync_all()?;
+    if let Some(permissions) = permissions {
+        fs::set_permissions(temp.path(), permissions)?;
+    }
+    temp.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
 /// Print a unified diff between original and formatted content.
 fn print_diff(name: &str, original: &str, formatted: &str) {
     let diff = TextDiff::from_lines(original, formatted);
@@ -545,6 +1059,21 @@ enum Error {
     /// Glob pattern error.
     #[error("invalid glob pattern: {0}")]
     Glob(#[from] glob::PatternError),
+    /// Git command failure.
+    #[error("git: {0}")]
+    Git(String),
+    /// The file changed on disk while it was being formatted.
+    #[error("file changed while formatting, skipped")]
+    Modified,
+    /// The file can't be mirrored into the output directory.
+    #[error("can't mirror a path outside the current directory into --output-dir")]
+    Unmirrorable,
+    /// A staged file also has unstaged changes, which re-staging would add.
+    #[error("file has unstaged changes; stage or stash them first")]
+    PartiallyStaged,
+    /// A pre-commit hook not written by nufmt is already installed.
+    #[error("{} already exists (use --force to overwrite)", .0.display())]
+    HookExists(PathBuf),
 }
 
 #[cfg(test)]
@@ -604,6 +1133,12 @@ mod tests {
             patterns: vec![],
             check: false,
             stdin: false,
+            staged: false,
+            files_from: None,
+            null: false,
+            no_cache: false,
+            output_dir: None,
+            copy_unchanged: false,
             config: None,
             color: ColorChoice::Auto,
             indent_width: None,
@@ -611,6 +1146,18 @@ mod tests {
             quote_style: None,
             bracket_spacing: None,
             trailing_comma: None,
+            pipe_position: None,
+            align_match_arrows: None,
+            align_tables: None,
+            align_record_values: None,
+            collection_layout: None,
+            block_layout: None,
+            flag_value_style: None,
+            redirection_style: None,
+            key_quotes: None,
+            number_case: None,
+            unit_case: None,
+            digit_grouping: None,
         };
 
         // When no config file exists, should use defaults
@@ -646,6 +1193,12 @@ mod tests {
             patterns: vec![file_path.display().to_string()],
             check: false,
             stdin: false,
+            staged: false,
+            files_from: None,
+            null: false,
+            no_cache: false,
+            output_dir: None,
+            copy_unchanged: false,
             config: None,
             color: ColorChoice::Auto,
             indent_width: None,
@@ -653,10 +1206,22 @@ mod tests {
             quote_style: None,
             bracket_spacing: None,
             trailing_comma: None,
+            pipe_position: None,
+            align_match_arrows: None,
+            align_tables: None,
+            align_record_values: None,
+            collection_layout: None,
+            block_layout: None,
+            flag_value_style: None,
+            redirection_style: None,
+            key_quotes: None,
+            number_case: None,
+            unit_case: None,
+            digit_grouping: None,
         };
         let config = Config::default();
 
-        let would_change = format_file(&file_path, &args, &config).unwrap();
+        let would_change = format_file(&file_path, &args, &config, None, temp_dir.path()).unwrap();
         assert!(would_change);
 
         // Verify file was formatted
@@ -677,6 +1242,12 @@ mod tests {
             patterns: vec![file_path.display().to_string()],
             check: true, // Check mode - don't modify
             stdin: false,
+            staged: false,
+            files_from: None,
+            null: false,
+            no_cache: false,
+            output_dir: None,
+            copy_unchanged: false,
             config: None,
             color: ColorChoice::Auto,
             indent_width: None,
@@ -684,10 +1255,22 @@ mod tests {
             quote_style: None,
             bracket_spacing: None,
             trailing_comma: None,
+            pipe_position: None,
+            align_match_arrows: None,
+            align_tables: None,
+            align_record_values: None,
+            collection_layout: None,
+            block_layout: None,
+            flag_value_style: None,
+            redirection_style: None,
+            key_quotes: None,
+            number_case: None,
+            unit_case: None,
+            digit_grouping: None,
         };
         let config = Config::default();
 
-        let would_change = format_file(&file_path, &args, &config).unwrap();
+        let would_change = format_file(&file_path, &args, &config, None, temp_dir.path()).unwrap();
         assert!(would_change);
 
         // Verify file was NOT modified in check mode
@@ -708,6 +1291,12 @@ mod tests {
             patterns: vec![file_path.display().to_string()],
             check: false,
             stdin: false,
+            staged: false,
+            files_from: None,
+            null: false,
+            no_cache: false,
+            output_dir: None,
+            copy_unchanged: false,
             config: None,
             color: ColorChoice::Auto,
             indent_width: None,
@@ -715,10 +1304,561 @@ mod tests {
             quote_style: None,
             bracket_spacing: None,
             trailing_comma: None,
+            pipe_position: None,
+            align_match_arrows: None,
+            align_tables: None,
+            align_record_values: None,
+            collection_layout: None,
+            block_layout: None,
+            flag_value_style: None,
+            redirection_style: None,
+            key_quotes: None,
+            number_case: None,
+            unit_case: None,
+            digit_grouping: None,
         };
         let config = Config::default();
 
-        let would_change = format_file(&file_path, &args, &config).unwrap();
+        let would_change = format_file(&file_path, &args, &config, None, temp_dir.path()).unwrap();
         assert!(!would_change);
     }
+
+    #[test]
+    fn test_install_hook_writes_check_hook() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let hook_path = temp_dir.path().join("hooks/pre-commit");
+
+        install_hook(&hook_path, false, false).unwrap();
+
+        let content = fs::read_to_string(&hook_path).unwrap();
+        assert!(content.contains(HOOK_MARKER));
+        assert!(content.contains("nufmt --check --staged"));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&hook_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o111, 0o111, "hook should be executable");
+        }
+    }
+
+    #[test]
+    fn test_install_hook_replaces_own_hook() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let hook_path = temp_dir.path().join("pre-commit");
+
+        install_hook(&hook_path, false, false).unwrap();
+        install_hook(&hook_path, true, false).unwrap();
+
+        let content = fs::read_to_string(&hook_path).unwrap();
+        assert!(content.contains("git add"));
+    }
+
+    #[test]
+    fn test_install_hook_refuses_foreign_hook() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let hook_path = temp_dir.path().join("pre-commit");
+        fs::write(&hook_path, "#!/bin/sh\nmake lint\n").unwrap();
+
+        let result = install_hook(&hook_path, false, false);
+        assert!(matches!(result, Err(Error::HookExists(_))));
+        assert_eq!(
+            fs::read_to_string(&hook_path).unwrap(),
+            "#!/bin/sh\nmake lint\n"
+        );
+
+        install_hook(&hook_path, false, true).unwrap();
+        assert!(fs::read_to_string(&hook_path).unwrap().contains(HOOK_MARKER));
+    }
+
+    /// Create a git repository with no commits in a temporary directory.
+    fn temp_repo() -> tempfile::TempDir {
+        let temp_dir = tempfile::tempdir().unwrap();
+        git(temp_dir.path(), &["init", "-q"]).unwrap();
+        temp_dir
+    }
+
+    #[test]
+    fn test_git_hooks_dir_honors_hooks_path() {
+        let repo = temp_repo();
+        let root = fs::canonicalize(repo.path()).unwrap();
+
+        let hooks = git_hooks_dir(repo.path()).unwrap();
+        assert_eq!(fs::canonicalize(hooks).unwrap(), root.join(".git/hooks"));
+
+        git(repo.path(), &["config", "core.hooksPath", "custom-hooks"]).unwrap();
+        let hooks = git_hooks_dir(repo.path()).unwrap();
+        assert_eq!(hooks, repo.path().join("custom-hooks"));
+    }
+
+    #[test]
+    fn test_staged_files_lists_staged_nu_files() {
+        let repo = temp_repo();
+        let root = fs::canonicalize(repo.path()).unwrap();
+        fs::create_dir(repo.path().join("sub")).unwrap();
+        fs::write(repo.path().join("sub/staged.nu"), "ls").unwrap();
+        fs::write(repo.path().join("unstaged.nu"), "ls").unwrap();
+        fs::write(repo.path().join("notes.txt"), "ls").unwrap();
+        git(repo.path(), &["add", "sub/staged.nu", "notes.txt"]).unwrap();
+
+        // Paths are resolved against the root, wherever git is run from
+        let staged = staged_files(&repo.path().join("sub")).unwrap();
+        assert_eq!(staged, vec![root.join("sub/staged.nu")]);
+    }
+
+    #[test]
+    fn test_partially_staged_file_is_checked_as_staged() {
+        let repo = temp_repo();
+        let root = fs::canonicalize(repo.path()).unwrap();
+        let file_path = repo.path().join("script.nu");
+        fs::write(&file_path, "ls|sort-by name").unwrap();
+        git(repo.path(), &["add", "script.nu"]).unwrap();
+        fs::write(&file_path, "ls | sort-by name\n").unwrap();
+
+        let unstaged = unstaged_files(repo.path()).unwrap();
+        assert!(unstaged.contains(&root.join("script.nu")));
+        assert_eq!(staged_source(&file_path).unwrap(), "ls|sort-by name");
+
+        let args = Args::parse_from(["nufmt", "--check", "--staged", "--no-cache"]);
+        let would_change = format_file(&file_path, &args, &Config::default(), None, &root).unwrap();
+        assert!(would_change, "the staged blob is unformatted");
+    }
+
+    #[test]
+    fn test_format_file_records_and_skips_cached() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let cache_dir = temp_dir.path().join("cache");
+        let file_path = temp_dir.path().join("test.nu");
+        fs::write(&file_path, "ls|sort-by name").unwrap();
+
+        let args = Args {
+            command: None,
+            patterns: vec![file_path.display().to_string()],
+            check: false,
+            stdin: false,
+            staged: false,
+            files_from: None,
+            null: false,
+            no_cache: false,
+            output_dir: None,
+            copy_unchanged: false,
+            config: None,
+            color: ColorChoice::Auto,
+            indent_width: None,
+            max_width: None,
+            quote_style: None,
+            bracket_spacing: None,
+            trailing_comma: None,
+            pipe_position: None,
+            align_match_arrows: None,
+            align_tables: None,
+            align_record_values: None,
+            collection_layout: None,
+            block_layout: None,
+            flag_value_style: None,
+            redirection_style: None,
+            key_quotes: None,
+            number_case: None,
+            unit_case: None,
+            digit_grouping: None,
+        };
+        let config = Config::default();
+
+        let cache = Cache::load(&cache_dir, &config);
+        assert!(format_file(&file_path, &args, &config, Some(&cache), temp_dir.path()).unwrap());
+        assert!(cache.contains("ls | sort-by name\n"));
+        cache.save().unwrap();
+
+        // A fresh load sees the formatted content from the previous run
+        let cache = Cache::load(&cache_dir, &config);
+        assert!(!format_file(&file_path, &args, &config, Some(&cache), temp_dir.path()).unwrap());
+
+        // A different config doesn't reuse those entries
+        let other = Config {
+            indent_width: 4,
+            ..Config::default()
+        };
+        assert!(!Cache::load(&cache_dir, &other).contains("ls | sort-by name\n"));
+    }
+
+    #[test]
+    fn test_cache_keeps_entries_saved_concurrently() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let config = Config::default();
+
+        let first = Cache::load(cache_dir.path(), &config);
+        let second = Cache::load(cache_dir.path(), &config);
+        first.insert("ls\n");
+        second.insert("pwd\n");
+        first.save().unwrap();
+        second.save().unwrap();
+
+        let cache = Cache::load(cache_dir.path(), &config);
+        assert!(cache.contains("ls\n"));
+        assert!(cache.contains("pwd\n"));
+    }
+
+    #[test]
+    fn test_cache_drops_least_recently_used_entries() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let config = Config::default();
+
+        let cache = Cache::load(cache_dir.path(), &config);
+        cache.insert("ls\n");
+        cache.save().unwrap();
+        let cache_file = fs::read_dir(cache_dir.path())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let filler: Vec<String> = (0..cache::MAX_ENTRIES)
+            .map(|i| format!("{i:064x}"))
+            .collect();
+        fs::write(&cache_file, filler.join("\n")).unwrap();
+
+        let cache = Cache::load(cache_dir.path(), &config);
+        cache.insert("pwd\n");
+        cache.save().unwrap();
+
+        let content = fs::read_to_string(&cache_file).unwrap();
+        assert_eq!(content.lines().count(), cache::MAX_ENTRIES);
+        let last = format!("{:064x}", cache::MAX_ENTRIES - 1);
+        assert!(!content.contains(&last));
+        assert!(Cache::load(cache_dir.path(), &config).contains("pwd\n"));
+    }
+
+    #[test]
+    fn test_cache_removes_stale_files() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let long_ago = std::time::SystemTime::now() - std::time::Duration::from_hours(90 * 24);
+        let stale = cache_dir.path().join("0".repeat(64));
+        let other = cache_dir.path().join("notes.txt");
+        for path in [&stale, &other] {
+            fs::write(path, "").unwrap();
+            let file = fs::File::options().write(true).open(path).unwrap();
+            file.set_modified(long_ago).unwrap();
+        }
+
+        let cache = Cache::load(cache_dir.path(), &Config::default());
+        cache.insert("ls\n");
+        cache.save().unwrap();
+
+        assert!(!stale.exists());
+        assert!(other.exists());
+    }
+
+    #[test]
+    fn test_format_file_check_mode_does_not_cache_unformatted() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_path = temp_dir.path().join("test.nu");
+        fs::write(&file_path, "ls|sort-by name").unwrap();
+
+        let args = Args {
+            command: None,
+            patterns: vec![file_path.display().to_string()],
+            check: true,
+            stdin: false,
+            staged: false,
+            files_from: None,
+            null: false,
+            no_cache: false,
+            output_dir: None,
+            copy_unchanged: false,
+            config: None,
+            color: ColorChoice::Auto,
+            indent_width: None,
+            max_width: None,
+            quote_style: None,
+            bracket_spacing: None,
+            trailing_comma: None,
+            pipe_position: None,
+            align_match_arrows: None,
+            align_tables: None,
+            align_record_values: None,
+            collection_layout: None,
+            block_layout: None,
+            flag_value_style: None,
+            redirection_style: None,
+            key_quotes: None,
+            number_case: None,
+            unit_case: None,
+            digit_grouping: None,
+        };
+        let config = Config::default();
+
+        let cache = Cache::load(temp_dir.path(), &config);
+        assert!(format_file(&file_path, &args, &config, Some(&cache), temp_dir.path()).unwrap());
+        assert!(!cache.contains("ls|sort-by name"));
+        assert!(!cache.contains("ls | sort-by name\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomic_preserves_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_path = temp_dir.path().join("script.nu");
+        fs::write(&file_path, "ls|sort-by name").unwrap();
+        fs::set_permissions(&file_path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let permissions = fs::metadata(&file_path).unwrap().permissions();
+        write_atomic(&file_path, "ls | sort-by name\n", Some(permissions)).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&file_path).unwrap(),
+            "ls | sort-by name\n"
+        );
+        let mode = fs::metadata(&file_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+
+        // No temporary files are left behind
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomic_keeps_symlinks() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let target_dir = temp_dir.path().join("dotfiles");
+        fs::create_dir(&target_dir).unwrap();
+        let target = target_dir.join("config.nu");
+        fs::write(&target, "ls|sort-by name").unwrap();
+        let link = temp_dir.path().join("config.nu");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        let permissions = fs::metadata(&link).unwrap().permissions();
+        write_atomic(&link, "ls | sort-by name\n", Some(permissions)).unwrap();
+
+        let link_type = fs::symlink_metadata(&link).unwrap().file_type();
+        assert!(link_type.is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "ls | sort-by name\n");
+        // The temporary file was created next to the target, not the link
+        assert_eq!(fs::read_dir(&target_dir).unwrap().count(), 1);
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_ensure_unmodified_detects_changes() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_path = temp_dir.path().join("test.nu");
+        fs::write(&file_path, "ls|sort-by name").unwrap();
+
+        let metadata = fs::metadata(&file_path).unwrap();
+        assert!(ensure_unmodified(&file_path, &metadata, "ls|sort-by name").is_ok());
+
+        fs::write(&file_path, "ls|sort-by size").unwrap();
+        let result = ensure_unmodified(&file_path, &metadata, "ls|sort-by name");
+        assert!(matches!(result, Err(Error::Modified)));
+    }
+
+    #[test]
+    fn test_mirror_path() {
+        let out = Path::new("out");
+        let root = Path::new("/project");
+        assert_eq!(
+            mirror_path(out, root, Path::new("./src/a.nu")).unwrap(),
+            Path::new("out/src/a.nu")
+        );
+        assert_eq!(
+            mirror_path(out, root, Path::new("/project/src/a.nu")).unwrap(),
+            Path::new("out/src/a.nu")
+        );
+    }
+
+    #[test]
+    fn test_mirror_path_rejects_outside_paths() {
+        let out = Path::new("out");
+        let root = Path::new("/project");
+        assert!(matches!(
+            mirror_path(out, root, Path::new("../a.nu")),
+            Err(Error::Unmirrorable)
+        ));
+        assert!(matches!(
+            mirror_path(out, root, Path::new("/elsewhere/a.nu")),
+            Err(Error::Unmirrorable)
+        ));
+    }
+
+    #[test]
+    fn test_output_dir_inside_inputs_is_not_reformatted() {
+        use std::ffi::OsStr;
+
+        let project = tempfile::tempdir().unwrap();
+        let input = project.path().join("a.nu");
+        fs::write(&input, "ls|sort-by name").unwrap();
+        let out = project.path().join("out");
+        let args = Args::parse_from([
+            OsStr::new("nufmt"),
+            OsStr::new("--no-cache"),
+            OsStr::new("--output-dir"),
+            out.as_os_str(),
+            project.path().as_os_str(),
+        ]);
+        let config = Config::default();
+        let run = || {
+            for path in collect_files(&args).unwrap() {
+                format_file(&path, &args, &config, None, project.path()).unwrap();
+            }
+        };
+
+        run();
+        let written = out.join("a.nu");
+        assert_eq!(fs::read_to_string(&written).unwrap(), "ls | sort-by name\n");
+        assert_eq!(fs::read_to_string(&input).unwrap(), "ls|sort-by name");
+
+        // A second run only sees the original input
+        assert_eq!(collect_files(&args).unwrap(), vec![input]);
+        run();
+        assert!(!out.join("out").exists());
+    }
+
+    #[test]
+    fn test_read_file_list_newline_separated() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let list_path = temp_dir.path().join("files.txt");
+        fs::write(&list_path, "a.nu\r\nsub dir/b.nu\n\nc[1].nu\n").unwrap();
+
+        let files = read_file_list(&list_path, false).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from("a.nu"),
+                PathBuf::from("sub dir/b.nu"),
+                PathBuf::from("c[1].nu"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_file_list_null_separated() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let list_path = temp_dir.path().join("files.txt");
+        fs::write(&list_path, "a.nu\0with\nnewline.nu\0").unwrap();
+
+        let files = read_file_list(&list_path, true).unwrap();
+        assert_eq!(
+            files,
+            vec![PathBuf::from("a.nu"), PathBuf::from("with\nnewline.nu")]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_file_list_non_utf8_paths() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let list_path = temp_dir.path().join("files.txt");
+        fs::write(&list_path, b"a.nu\0caf\xe9.nu\0").unwrap();
+
+        let files = read_file_list(&list_path, true).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from("a.nu"),
+                PathBuf::from(OsStr::from_bytes(b"caf\xe9.nu")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_block_layout_flag_uses_config_spelling() {
+        let args = Args::parse_from(["nufmt", "--block-layout", "always_expand_defs"]);
+        assert_eq!(args.block_layout, Some(BlockLayout::AlwaysExpandDefs));
+        assert!(Args::try_parse_from(["nufmt", "--block-layout", "always-expand-defs"]).is_err());
+    }
+
+    #[test]
+    fn test_key_quotes_flag_uses_config_spelling() {
+        let args = Args::parse_from(["nufmt", "--key-quotes", "as_needed"]);
+        assert_eq!(args.key_quotes, Some(KeyQuotes::AsNeeded));
+        assert!(Args::try_parse_from(["nufmt", "--key-quotes", "as-needed"]).is_err());
+    }
+
+    #[test]
+    fn test_install_hook_refuses_unreadable_foreign_hook() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let hook_path = temp_dir.path().join("pre-commit");
+        let foreign = b"#!/bin/sh\n# \xff\xfe\nmake lint\n";
+        fs::write(&hook_path, foreign).unwrap();
+
+        let result = install_hook(&hook_path, false, false);
+        assert!(matches!(result, Err(Error::HookExists(_))));
+        assert_eq!(fs::read(&hook_path).unwrap(), foreign);
+
+        #[cfg(unix)]
+        {
+            let dangling = temp_dir.path().join("dangling");
+            std::os::unix::fs::symlink(temp_dir.path().join("missing"), &dangling).unwrap();
+            let result = install_hook(&dangling, false, false);
+            assert!(matches!(result, Err(Error::HookExists(_))));
+        }
+
+        install_hook(&hook_path, false, true).unwrap();
+        assert!(
+            fs::read_to_string(&hook_path)
+                .unwrap()
+                .contains(HOOK_MARKER)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_staged_files_with_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let repo = temp_repo();
+        let root = fs::canonicalize(repo.path()).unwrap();
+        let name = OsStr::from_bytes(b"caf\xe9.nu");
+        let file_path = repo.path().join(name);
+        fs::write(&file_path, "ls|sort-by name").unwrap();
+        git(repo.path(), &[OsStr::new("add"), name]).unwrap();
+        fs::write(&file_path, "ls | sort-by name\n").unwrap();
+
+        assert_eq!(staged_files(repo.path()).unwrap(), vec![root.join(name)]);
+        assert!(
+            unstaged_files(repo.path())
+                .unwrap()
+                .contains(&root.join(name))
+        );
+        assert_eq!(staged_source(&file_path).unwrap(), "ls|sort-by name");
+    }
+
+    #[test]
+    fn test_staged_file_deleted_from_working_tree_is_checked() {
+        let repo = temp_repo();
+        let root = fs::canonicalize(repo.path()).unwrap();
+        let file_path = repo.path().join("script.nu");
+        fs::write(&file_path, "ls|sort-by name").unwrap();
+        git(repo.path(), &["add", "script.nu"]).unwrap();
+        fs::remove_file(&file_path).unwrap();
+
+        assert_eq!(
+            staged_files(repo.path()).unwrap(),
+            vec![root.join("script.nu")]
+        );
+        let args = Args::parse_from(["nufmt", "--check", "--staged", "--no-cache"]);
+        let would_change = format_file(&file_path, &args, &Config::default(), None, &root).unwrap();
+        assert!(would_change, "the staged blob is unformatted");
+    }
+
+    #[test]
+    fn test_cache_keeps_every_entry_used_in_a_run() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let config = Config::default();
+        let sources: Vec<String> = (0..=cache::MAX_ENTRIES)
+            .map(|i| format!("echo {i}\n"))
+            .collect();
+
+        let cache = Cache::load(cache_dir.path(), &config);
+        for source in &sources {
+            cache.insert(source);
+        }
+        cache.save().unwrap();
+
+        let cache = Cache::load(cache_dir.path(), &config);
+        assert!(sources.iter().all(|source| cache.contains(source)));
+    }
 }
@@ -1,11 +1,11 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 #[cfg(feature = "cli")]
 use clap::ValueEnum;
 
 /// Preferred quote style for strings.
-#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
 #[cfg_attr(feature = "cli", derive(ValueEnum))]
 #[serde(rename_all = "lowercase")]
 pub enum QuoteStyle {
@@ -19,7 +19,7 @@ pub enum QuoteStyle {
 }
 
 /// Whether to add spaces inside brackets/braces.
-#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
 #[cfg_attr(feature = "cli", derive(ValueEnum))]
 #[serde(rename_all = "lowercase")]
 pub enum BracketSpacing {
@@ -31,7 +31,7 @@ pub enum BracketSpacing {
 }
 
 /// Whether to add trailing commas in multiline collections.
-#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
 #[cfg_attr(feature = "cli", derive(ValueEnum))]
 #[serde(rename_all = "lowercase")]
 pub enum TrailingComma {
@@ -42,6 +42,126 @@ pub enum TrailingComma {
     Never,
 }
 
+/// Where to place `|` when a pipeline is broken across lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum PipePosition {
+    /// Start each continuation line with `| ` (default).
+    #[default]
+    Leading,
+    /// End each broken line with ` |`.
+    Trailing,
+}
+
+/// How to choose between one-line and multiline layouts for lists and records.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum CollectionLayout {
+    /// Keep collections multiline if they were, breaking others only when too long (default).
+    #[default]
+    Preserve,
+    /// Join collections that fit in `max_width`, putting one item per line otherwise.
+    Auto,
+    /// Like `auto`, but pack as many list items onto each line as fit.
+    Fill,
+}
+
+/// When to put the body of a brace block on its own lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "cli", derive(ValueEnum), value(rename_all = "snake_case"))]
+#[serde(rename_all = "snake_case")]
+pub enum BlockLayout {
+    /// Keep blocks multiline or single-line as written (default).
+    #[default]
+    Preserve,
+    /// Collapse single-statement blocks that fit, expand blocks with several statements.
+    Auto,
+    /// Like `auto`, but always expand `def` bodies.
+    AlwaysExpandDefs,
+}
+
+/// How to join a long flag to its value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum FlagValueStyle {
+    /// Keep `--flag=value` or `--flag value` as written (default).
+    #[default]
+    Preserve,
+    /// Write `--flag=value`.
+    Equals,
+    /// Write `--flag value`.
+    Space,
+}
+
+/// How to spell redirection operators.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum RedirectionStyle {
+    /// Keep redirections as written (default).
+    #[default]
+    Preserve,
+    /// Write short forms like `o>`, `e>`, and `o+e>`.
+    Short,
+    /// Write long forms like `out>`, `err>`, and `out+err>`.
+    Long,
+}
+
+/// When to quote record keys and cell-path members.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "cli", derive(ValueEnum), value(rename_all = "snake_case"))]
+#[serde(rename_all = "snake_case")]
+pub enum KeyQuotes {
+    /// Keep keys quoted or bare as written (default).
+    #[default]
+    Preserve,
+    /// Quote only the keys that can't be bare words.
+    AsNeeded,
+    /// Like `as_needed`, but quote every key of a record or cell path if one needs it.
+    Consistent,
+}
+
+/// How to write the letters of number literals.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum NumberCase {
+    /// Keep numbers as written (default).
+    #[default]
+    Preserve,
+    /// Lowercase hex digits, like `0xff` and `0x[ff]`, and float exponents, like `1e5`.
+    Lower,
+}
+
+/// How to write the units of filesize and duration literals.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum UnitCase {
+    /// Keep units as written (default).
+    #[default]
+    Preserve,
+    /// Write filesize units in lowercase, like `kb` and `mib`.
+    Lower,
+    /// Write units the way Nushell prints them, like `kB`, `MiB`, and `µs`.
+    Canonical,
+}
+
+/// How to group the digits of long integers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum DigitGrouping {
+    /// Keep integers as written (default).
+    #[default]
+    Preserve,
+    /// Group the digits of integers with five or more in threes, like `10_000`.
+    Thousands,
+}
+
 /// Configuration validation error.
 #[derive(Debug, Clone, PartialEq, Eq, Error)]
 #[error("{message}")]
@@ -51,7 +171,7 @@ pub struct ConfigError {
 }
 
 /// Formatting configuration options.
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(default)]
 pub struct Config {
     /// Number of spaces per indentation level (1-16, default: 2).
@@ -64,6 +184,30 @@ pub struct Config {
     pub bracket_spacing: BracketSpacing,
     /// Whether to add trailing commas in multiline collections.
     pub trailing_comma: TrailingComma,
+    /// Where to place `|` in pipelines broken across lines.
+    pub pipe_position: PipePosition,
+    /// Whether to column-align `=>` across the arms of a `match`.
+    pub align_match_arrows: bool,
+    /// Whether to pad the cells of table literals so their columns line up.
+    pub align_tables: bool,
+    /// Whether to pad record keys so the values of a multiline record line up.
+    pub align_record_values: bool,
+    /// How to choose between one-line and multiline layouts for lists and records.
+    pub collection_layout: CollectionLayout,
+    /// When to put the body of a brace block on its own lines.
+    pub block_layout: BlockLayout,
+    /// How to join a long flag to its value.
+    pub flag_value_style: FlagValueStyle,
+    /// How to spell redirection operators.
+    pub redirection_style: RedirectionStyle,
+    /// When to quote record keys and cell-path members.
+    pub key_quotes: KeyQuotes,
+    /// How to write the letters of number literals.
+    pub number_case: NumberCase,
+    /// How to write the units of filesize and duration literals.
+    pub unit_case: UnitCase,
+    /// How to group the digits of long integers.
+    pub digit_grouping: DigitGrouping,
 }
 
 impl Config {
@@ -101,6 +245,18 @@ impl Default for Config {
             quote_style: QuoteStyle::default(),
             bracket_spacing: BracketSpacing::default(),
             trailing_comma: TrailingComma::default(),
+            pipe_position: PipePosition::default(),
+            align_match_arrows: false,
+            align_tables: true,
+            align_record_values: false,
+            collection_layout: CollectionLayout::default(),
+            block_layout: BlockLayout::default(),
+            flag_value_style: FlagValueStyle::default(),
+            redirection_style: RedirectionStyle::default(),
+            key_quotes: KeyQuotes::default(),
+            number_case: NumberCase::default(),
+            unit_case: UnitCase::default(),
+            digit_grouping: DigitGrouping::default(),
         }
     }
 }
@@ -159,6 +315,18 @@ mod tests {
             quote_style: QuoteStyle::Single,
             bracket_spacing: BracketSpacing::Spaced,
             trailing_comma: TrailingComma::Always,
+            pipe_position: PipePosition::Trailing,
+            align_match_arrows: true,
+            align_tables: false,
+            align_record_values: true,
+            collection_layout: CollectionLayout::Fill,
+            block_layout: BlockLayout::AlwaysExpandDefs,
+            flag_value_style: FlagValueStyle::Equals,
+            redirection_style: RedirectionStyle::Short,
+            key_quotes: KeyQuotes::Consistent,
+            number_case: NumberCase::Lower,
+            unit_case: UnitCase::Canonical,
+            digit_grouping: DigitGrouping::Thousands,
         };
         assert!(config.validate().is_ok());
     }
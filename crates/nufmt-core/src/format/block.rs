@@ -0,0 +1,265 @@
+//! Discovery of brace blocks and what kind of body each one is.
+
+use std::collections::HashMap;
+
+use nu_protocol::{
+    BlockId,
+    ast::{Block, Expr, Expression, Traverse},
+    engine::StateWorkingSet,
+};
+
+use unicode_width::UnicodeWidthStr;
+
+use super::{closure::parse_closure_params, signature::Param};
+use crate::BlockLayout;
+
+/// What a brace block is the body of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockKind {
+    /// A closure passed to a command, like `each {|x| ... }`.
+    Closure,
+    /// The body of a `def`.
+    Def,
+    /// A branch or loop body of `if`, `try`, `for`, `match`, and friends.
+    ControlFlow,
+}
+
+/// A brace block, located by byte offsets in the source.
+#[derive(Debug, Clone)]
+pub struct BraceBlock {
+    pub kind: BlockKind,
+    /// Offset of the opening `{`.
+    pub open: usize,
+    /// Offset of the closing `}`.
+    pub close: usize,
+    /// Start offset of each statement.
+    pub statements: Vec<usize>,
+    /// Whether the statements span lines outside nested blocks, or the block
+    /// holds a comment.
+    pub multiline: bool,
+    /// Width of the whole block laid out on one line.
+    pub flat_width: usize,
+    /// Whether the block is expanded however short it is.
+    pub must_expand: bool,
+}
+
+/// Commands whose block arguments are control-flow bodies.
+const CONTROL_FLOW: [&str; 6] = ["if", "try", "for", "while", "loop", "match"];
+
+/// Collect every brace block in the parsed source, keyed by its `{` offset.
+pub fn collect_blocks(
+    working_set: &StateWorkingSet,
+    block: &Block,
+    source: &str,
+    layout: BlockLayout,
+) -> HashMap<usize, BraceBlock> {
+    let mut found = Vec::new();
+    block.flat_map(
+        working_set,
+        &|expr: &Expression| blocks_in(working_set, expr, source),
+        &mut found,
+    );
+
+    // A block found as the argument of a call knows its kind better than the
+    // same block found on its own, so those entries go in last
+    found.sort_by_key(|(from_call, _)| *from_call);
+    let mut blocks: HashMap<usize, BraceBlock> = found
+        .into_iter()
+        .map(|(_, block)| (block.open, block))
+        .collect();
+
+    // Line breaks inside a nested block say nothing about the outer one,
+    // whose layout follows from the nested block's own
+    let spans: Vec<(usize, usize)> = blocks.values().map(|b| (b.open, b.close)).collect();
+    for block in blocks.values_mut() {
+        let Some(&start) = block.statements.first() else {
+            continue;
+        };
+        let body = source[start..block.close].trim_end();
+        block.multiline |= body.match_indices('\n').any(|(i, _)| {
+            let pos = start + i;
+            !spans
+                .iter()
+                .any(|&(open, close)| open > block.open && open < pos && pos < close)
+        });
+    }
+    mark_forced_expansions(&mut blocks, layout);
+    blocks
+}
+
+/// Flag the blocks that are expanded however short they are.
+///
+/// That is the case with several statements, a statement spanning lines,
+/// a comment, a `def` body under `always_expand_defs`, or a nested block
+/// that is expanded itself. Blocks are visited innermost first, each
+/// passing its flag on to the block around it.
+fn mark_forced_expansions(blocks: &mut HashMap<usize, BraceBlock>, layout: BlockLayout) {
+    let mut spans: Vec<(usize, usize)> = blocks.values().map(|b| (b.open, b.close)).collect();
+    spans.sort_unstable();
+
+    let mut parents = HashMap::new();
+    let mut stack: Vec<(usize, usize)> = Vec::new();
+    for &(open, close) in &spans {
+        while stack.last().is_some_and(|&(_, outer)| outer < open) {
+            stack.pop();
+        }
+        if let Some(&(outer, _)) = stack.last() {
+            parents.insert(open, outer);
+        }
+        stack.push((open, close));
+    }
+
+    for &(open, _) in spans.iter().rev() {
+        let block = &blocks[&open];
+        let forced = block.must_expand
+            || block.statements.len() > 1
+            || block.multiline
+            || block.kind == BlockKind::Def && layout == BlockLayout::AlwaysExpandDefs;
+        blocks
+            .get_mut(&open)
+            .expect("span of a known block")
+            .must_expand = forced;
+        if forced && let Some(parent) = parents.get(&open) {
+            blocks
+                .get_mut(parent)
+                .expect("span of a known block")
+                .must_expand = true;
+        }
+    }
+}
+
+/// Find the blocks an expression introduces, flagging those whose kind comes
+/// from the call they are an argument of.
+fn blocks_in(
+    working_set: &StateWorkingSet,
+    expr: &Expression,
+    source: &str,
+) -> Vec<(bool, BraceBlock)> {
+    match &expr.expr {
+        Expr::Closure(id) | Expr::RowCondition(id) => {
+            brace_block(working_set, expr, *id, BlockKind::Closure, source)
+                .map(|b| (false, b))
+                .into_iter()
+                .collect()
+        }
+        Expr::Block(id) => brace_block(working_set, expr, *id, BlockKind::ControlFlow, source)
+            .map(|b| (false, b))
+            .into_iter()
+            .collect(),
+        Expr::Call(call) => {
+            let name = working_set.get_decl(call.decl_id).name();
+            let kind = match name {
+                "def" | "export def" => BlockKind::Def,
+                name if CONTROL_FLOW.contains(&name) => BlockKind::ControlFlow,
+                _ => return Vec::new(),
+            };
+            call.positional_iter()
+                .map(|arg| match &arg.expr {
+                    Expr::Keyword(keyword) => &keyword.expr,
+                    _ => arg,
+                })
+                .filter_map(|arg| match arg.expr {
+                    Expr::Closure(id) | Expr::Block(id) => {
+                        brace_block(working_set, arg, id, kind, source)
+                    }
+                    _ => None,
+                })
+                .map(|b| (true, b))
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Build a `BraceBlock` from a block or closure expression.
+fn brace_block(
+    working_set: &StateWorkingSet,
+    expr: &Expression,
+    id: BlockId,
+    kind: BlockKind,
+    source: &str,
+) -> Option<BraceBlock> {
+    let span = expr.span;
+    if span.end <= span.start
+        || source.get(span.start..=span.start) != Some("{")
+        || source.get(span.end - 1..span.end) != Some("}")
+    {
+        return None;
+    }
+    let (open, close) = (span.start, span.end - 1);
+
+    let statements: Vec<usize> = working_set
+        .get_block(body_block(working_set, id))
+        .pipelines
+        .iter()
+        .filter_map(|p| p.elements.first())
+        .map(|e| e.expr.span.start)
+        .collect();
+    Some(BraceBlock {
+        kind,
+        open,
+        close,
+        statements,
+        multiline: source[open..close].contains('#'),
+        flat_width: flat_block_width(&source[open..=close]),
+        must_expand: false,
+    })
+}
+
+/// The block holding the statements of block `id`.
+///
+/// A body that uses `$in` is parsed as a single collect of a subexpression
+/// spanning the whole block, which holds the actual statements.
+fn body_block(working_set: &StateWorkingSet, id: BlockId) -> BlockId {
+    let block = working_set.get_block(id);
+    match block.pipelines.as_slice() {
+        [pipeline] => match pipeline.elements.as_slice() {
+            [element] => match &element.expr.expr {
+                Expr::Collect(_, inner) => match inner.expr {
+                    Expr::Subexpression(inner_id) | Expr::Block(inner_id) => inner_id,
+                    _ => id,
+                },
+                _ => id,
+            },
+            _ => id,
+        },
+        _ => id,
+    }
+}
+
+/// Start offsets of every statement but the first, across all blocks,
+/// mapped to the opening brace offset of their block.
+pub fn later_statements(blocks: &HashMap<usize, BraceBlock>) -> HashMap<usize, usize> {
+    blocks
+        .values()
+        .flat_map(|b| b.statements.iter().skip(1).map(|&start| (start, b.open)))
+        .collect()
+}
+
+/// Width of a block laid out on one line, as `{ body }` or
+/// `{|params| body }`, with its parameters normalized and whitespace
+/// collapsed.
+fn flat_block_width(text: &str) -> usize {
+    let (params, body) = parse_closure_params(&text[1..text.len() - 1]);
+    let body = collapsed_width(body);
+    if body == 0 {
+        return collapsed_width(text);
+    }
+    let params = params.map_or(0, |p| {
+        p.list.map_or_else(
+            || p.text.width(),
+            |list| {
+                let rendered: Vec<String> = list.params.iter().map(Param::render).collect();
+                format!("|{}|", rendered.join(", ")).width()
+            },
+        )
+    });
+    "{ ".len() + params + body + " }".len()
+}
+
+/// Display width of `text` with each run of whitespace collapsed to one space.
+pub fn collapsed_width(text: &str) -> usize {
+    let words = text.split_whitespace();
+    let count = words.clone().count();
+    words.map(UnicodeWidthStr::width).sum::<usize>() + count.saturating_sub(1)
+}
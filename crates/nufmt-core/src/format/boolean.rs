@@ -0,0 +1,118 @@
+//! Discovery of `and` / `or` conditions that can be broken across lines.
+
+use std::collections::HashMap;
+
+use nu_protocol::{
+    ast::{Block, Expr, Expression, Operator, Traverse},
+    engine::StateWorkingSet,
+};
+
+use super::wrap::{Wrap, only_expression, parenthesized_expression};
+
+/// Commands whose first argument is a condition.
+const CONDITION_COMMANDS: [&str; 3] = ["if", "while", "where"];
+
+/// Collect every condition with two or more clauses, keyed by start offset.
+///
+/// Each clause joined by an operator of the lowest precedence in the
+/// condition is a piece, so that `$a and $b` in `$a and $b or $c` stays
+/// together.
+pub fn collect_conditions(
+    working_set: &StateWorkingSet,
+    block: &Block,
+    source: &str,
+) -> HashMap<usize, Wrap> {
+    let mut found = Vec::new();
+    block.flat_map(
+        working_set,
+        &|expr: &Expression| condition(working_set, expr, source).into_iter().collect(),
+        &mut found,
+    );
+    found.into_iter().map(|w: Wrap| (w.start, w)).collect()
+}
+
+/// Build a `Wrap` from the condition of an `if`, `while`, or `where` call.
+fn condition(working_set: &StateWorkingSet, expr: &Expression, source: &str) -> Option<Wrap> {
+    let Expr::Call(call) = &expr.expr else {
+        return None;
+    };
+    if !CONDITION_COMMANDS.contains(&working_set.get_decl(call.decl_id).name()) {
+        return None;
+    }
+    let mut arg = call.positional_nth(0)?;
+    let row_condition = if let Expr::RowCondition(id) = arg.expr {
+        arg = only_expression(working_set, id)?;
+        true
+    } else {
+        false
+    };
+
+    let text = source.get(arg.span.start..arg.span.end)?;
+    let inner = parenthesized_expression(working_set, arg, source);
+    let parenthesized = inner.is_some();
+    let inner = inner.unwrap_or(arg);
+
+    // Parentheses would turn the bare column names of a row condition into
+    // commands, so those can only be broken if they are already wrapped
+    if text.contains('#')
+        || !parenthesized
+            && (text.contains('\n') || row_condition && has_bare_column(working_set, arg, source))
+    {
+        return None;
+    }
+
+    let Expr::BinaryOp(_, op, _) = &inner.expr else {
+        return None;
+    };
+    let Expr::Operator(top @ Operator::Boolean(_)) = op.expr else {
+        return None;
+    };
+    let mut operators = Vec::new();
+    logical_operators(inner, top.precedence(), &mut operators);
+
+    Some(Wrap {
+        start: arg.span.start,
+        end: arg.span.end,
+        parenthesized,
+        hug_open: false,
+        breaks: operators,
+        measure_end: arg.span.end,
+        trailing_width: " {".len(),
+        multiline: text.contains('\n'),
+    })
+}
+
+/// Collect the start offsets of the logical operators of `precedence` that
+/// join the clauses of `expr`.
+fn logical_operators(expr: &Expression, precedence: u8, operators: &mut Vec<usize>) {
+    if let Expr::BinaryOp(lhs, op, rhs) = &expr.expr
+        && let Expr::Operator(operator @ Operator::Boolean(_)) = op.expr
+        && operator.precedence() == precedence
+    {
+        logical_operators(lhs, precedence, operators);
+        operators.push(op.span.start);
+        logical_operators(rhs, precedence, operators);
+    }
+}
+
+/// Whether a row condition refers to a column by its bare name, like `size`
+/// rather than `$it.size`.
+fn has_bare_column(working_set: &StateWorkingSet, expr: &Expression, source: &str) -> bool {
+    let mut found = Vec::new();
+    expr.flat_map(
+        working_set,
+        &|e: &Expression| {
+            let head = match &e.expr {
+                Expr::FullCellPath(path) if matches!(path.head.expr, Expr::Var(_)) => &path.head,
+                Expr::Var(_) => e,
+                _ => return Vec::new(),
+            };
+            let bare = source
+                .get(head.span.start..head.span.end)
+                .is_none_or(|text| !text.starts_with('$'));
+            if bare { vec![()] } else { Vec::new() }
+        },
+        &mut found,
+    );
+    !found.is_empty()
+}
@@ -0,0 +1,321 @@
+//! Discovery of command arguments that must stay unspaced, and of calls
+//! whose arguments can be wrapped.
+//!
+//! An environment prefix like `FOO=bar ^cmd` flattens into brace-less
+//! `Record` tokens, and a `--flag=value` argument leaves its `=` in the gap
+//! after the flag, so both are located from the parsed AST instead.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+};
+
+use nu_protocol::{
+    Span,
+    ast::{Argument, Block, Expr, Expression, ExternalArgument, RecordItem, Traverse},
+    engine::StateWorkingSet,
+};
+
+use super::wrap::{Wrap, parenthesized_expression};
+
+/// The `NAME=value` assignments before a command, located by byte offsets in
+/// the source.
+#[derive(Debug, Clone)]
+pub struct EnvPrefix {
+    /// Byte range of each assignment.
+    pub assignments: Vec<Range<usize>>,
+}
+
+impl EnvPrefix {
+    /// Offset just past the last assignment.
+    pub fn end(&self) -> usize {
+        self.assignments.last().map_or(0, |a| a.end)
+    }
+
+    /// Render the assignments as written, separated by single spaces.
+    pub fn render(&self, source: &str) -> String {
+        self.assignments
+            .iter()
+            .map(|a| &source[a.clone()])
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Collect every environment prefix in the parsed source, keyed by the
+/// offset of its first assignment.
+pub fn collect_env_prefixes(
+    working_set: &StateWorkingSet,
+    block: &Block,
+    source: &str,
+) -> HashMap<usize, EnvPrefix> {
+    let mut found = Vec::new();
+    block.flat_map(
+        working_set,
+        &|expr: &Expression| env_prefix(working_set, expr, source).into_iter().collect(),
+        &mut found,
+    );
+    found
+        .into_iter()
+        .map(|prefix: EnvPrefix| (prefix.assignments[0].start, prefix))
+        .collect()
+}
+
+/// Build an `EnvPrefix` from the `with-env` call the parser turns it into.
+fn env_prefix(working_set: &StateWorkingSet, expr: &Expression, source: &str) -> Option<EnvPrefix> {
+    let Expr::Call(call) = &expr.expr else {
+        return None;
+    };
+    // The call has no head of its own when it comes from the shorthand
+    if call.head != Span::unknown() || working_set.get_decl(call.decl_id).name() != "with-env" {
+        return None;
+    }
+    let Some(Argument::Positional(record)) = call.arguments.first() else {
+        return None;
+    };
+    let Expr::Record(items) = &record.expr else {
+        return None;
+    };
+
+    let assignments = items
+        .iter()
+        .map(|item| {
+            let RecordItem::Pair(name, value) = item else {
+                return None;
+            };
+            // `FOO= cmd` sets an empty value, which has no span
+            let end = if value.span == Span::unknown() {
+                name.span.end + "=".len()
+            } else {
+                value.span.end
+            };
+            let text = source.get(name.span.start..end)?;
+            (source.get(name.span.end..=name.span.end) == Some("=") && !text.contains('\n'))
+                .then_some(name.span.start..end)
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    (!assignments.is_empty()).then_some(EnvPrefix { assignments })
+}
+
+/// Collect the start offset of every long flag's value, for flags that take
+/// one and whose value follows on the same line.
+///
+/// Switches are left out: `--all=false` can't be written `--all false`.
+pub fn collect_flag_values(
+    working_set: &StateWorkingSet,
+    block: &Block,
+    source: &str,
+) -> HashSet<usize> {
+    let mut found = Vec::new();
+    block.flat_map(
+        working_set,
+        &|expr: &Expression| flag_values(working_set, expr, source),
+        &mut found,
+    );
+    found.into_iter().collect()
+}
+
+/// Find the values of the long flags of a single call.
+fn flag_values(working_set: &StateWorkingSet, expr: &Expression, source: &str) -> Vec<usize> {
+    let Expr::Call(call) = &expr.expr else {
+        return Vec::new();
+    };
+    let signature = working_set.get_decl(call.decl_id).signature();
+
+    call.arguments
+        .iter()
+        .filter_map(|arg| match arg {
+            Argument::Named((long, _, Some(value))) => Some((long, value)),
+            _ => None,
+        })
+        .filter(|(long, value)| {
+            let takes_value = signature
+                .get_long_flag(&long.item)
+                .is_some_and(|flag| flag.arg.is_some());
+            let between = source.get(long.span.end..value.span.start);
+            takes_value
+                && source
+                    .get(long.span.start..long.span.end)
+                    .is_some_and(|flag| flag.starts_with("--"))
+                && between.is_some_and(|b| {
+                    b == "=" || !b.is_empty() && b.trim_matches([' ', '\t']).is_empty()
+                })
+        })
+        .map(|(_, value)| value.span.start)
+        .collect()
+}
+
+/// Collect every command call with two or more arguments, keyed by start
+/// offset.
+///
+/// Each argument is a piece, with a flag and its value kept together. Calls
+/// after an environment prefix are left out, as they can't be wrapped in
+/// parentheses of their own.
+pub fn collect_calls(
+    working_set: &StateWorkingSet,
+    block: &Block,
+    source: &str,
+    env_prefixes: &HashMap<usize, EnvPrefix>,
+) -> HashMap<usize, Wrap> {
+    let mut found = Vec::new();
+    block.flat_map(
+        working_set,
+        &|expr: &Expression| {
+            let (call, parens) = parenthesized_expression(working_set, expr, source)
+                .map_or((expr, None), |inner| (inner, Some(expr.span)));
+            call_wrap(working_set, call, parens, source)
+                .into_iter()
+                .collect()
+        },
+        &mut found,
+    );
+
+    // A call is found both on its own and inside its parentheses, and the
+    // latter knows better, so those entries go in last
+    found.sort_by_key(|(_, w): &(usize, Wrap)| w.parenthesized);
+    let by_head: HashMap<usize, Wrap> = found.into_iter().collect();
+    by_head
+        .into_values()
+        .filter(|w| {
+            !env_prefixes.values().any(|prefix| {
+                source
+                    .get(prefix.end()..w.start)
+                    .is_some_and(|between| between.trim().is_empty())
+            })
+        })
+        .map(|w| (w.start, w))
+        .collect()
+}
+
+/// Build a `Wrap` from a command call, keyed by the start of its name.
+///
+/// Keywords like `let` and `if`, and `where` with its row condition, have
+/// layouts of their own and are left out.
+fn call_wrap(
+    working_set: &StateWorkingSet,
+    expr: &Expression,
+    parens: Option<Span>,
+    source: &str,
+) -> Option<(usize, Wrap)> {
+    let (head, pieces) = match &expr.expr {
+        Expr::Call(call) => {
+            let decl = working_set.get_decl(call.decl_id);
+            if call.head == Span::unknown() || decl.is_keyword() || decl.name() == "where" {
+                return None;
+            }
+            let pieces = call
+                .arguments
+                .iter()
+                .map(|arg| {
+                    let span = arg.span();
+                    let start = match arg {
+                        Argument::Spread(_) => span.start.checked_sub("...".len())?,
+                        _ => span.start,
+                    };
+                    source.get(start..span.end)?;
+                    Some((start..span.end, arg.expr()))
+                })
+                .collect::<Option<Vec<_>>>()?;
+            (call.head, pieces)
+        }
+        Expr::ExternalCall(head, args) => {
+            // The span of the name leaves out a leading `^`
+            let caret = source.get(..head.span.start)?.ends_with('^');
+            let start = head.span.start - usize::from(caret);
+            (
+                Span::new(start, head.span.end),
+                external_pieces(args, source)?,
+            )
+        }
+        _ => return None,
+    };
+    if pieces.len() < 2 {
+        return None;
+    }
+
+    // Everything between the name and the arguments must be whitespace
+    let mut separators = Vec::new();
+    let mut cursor = head.end;
+    for (range, _) in &pieces {
+        let between = source.get(cursor..range.start)?;
+        if between.is_empty() || !between.trim().is_empty() {
+            return None;
+        }
+        separators.push(between);
+        cursor = range.end;
+    }
+
+    // A closure, or a list or record that is last or already multiline, hugs
+    // the call, so only the line up to its opening bracket is measured
+    let hug = pieces.iter().enumerate().position(|(i, (range, value))| {
+        let value = value.map(|v| match &v.expr {
+            Expr::FullCellPath(path) if path.tail.is_empty() => &path.head,
+            _ => v,
+        });
+        value.is_some_and(|v| match v.expr {
+            Expr::Closure(_) | Expr::Block(_) => true,
+            Expr::List(_) | Expr::Record(_) => {
+                i + 1 == pieces.len() || source[range.clone()].contains('\n')
+            }
+            _ => false,
+        })
+    });
+    let measure_end = hug.map_or(cursor, |i| pieces[i].1.map_or(cursor, |v| v.span.start + 1));
+    let multiline = separators[..=hug.unwrap_or(pieces.len() - 1)]
+        .iter()
+        .any(|s| s.contains('\n'));
+
+    let (start, end) = parens.map_or((head.start, cursor), |p| (p.start, p.end));
+    Some((
+        head.start,
+        Wrap {
+            start,
+            end,
+            parenthesized: parens.is_some(),
+            hug_open: true,
+            breaks: pieces.iter().map(|(range, _)| range.start).collect(),
+            measure_end,
+            trailing_width: 0,
+            multiline,
+        },
+    ))
+}
+
+/// Split the arguments of an external command into pieces.
+///
+/// Which flags of an external command take a value is unknown, so a flag
+/// without a `=value` of its own is kept together with an argument right
+/// after it on the same line that isn't a flag itself, as in
+/// `-H "Accept: text/html"`.
+fn external_pieces<'a>(
+    args: &'a [ExternalArgument],
+    source: &str,
+) -> Option<Vec<(Range<usize>, Option<&'a Expression>)>> {
+    let mut pieces: Vec<(Range<usize>, Option<&Expression>)> = Vec::new();
+    let mut after_flag = false;
+    for arg in args {
+        let span = arg.expr().span;
+        let start = match arg {
+            ExternalArgument::Spread(_) => span.start.checked_sub("...".len())?,
+            ExternalArgument::Regular(_) => span.start,
+        };
+        let text = source.get(start..span.end)?;
+        let is_flag = matches!(arg, ExternalArgument::Regular(_)) && text.starts_with('-');
+        match pieces.last_mut() {
+            Some((range, _)) if after_flag && !is_flag => {
+                let between = source.get(range.end..start)?;
+                if between.contains('\n') || !between.trim().is_empty() {
+                    pieces.push((start..span.end, Some(arg.expr())));
+                } else {
+                    range.end = span.end;
+                }
+                after_flag = false;
+                continue;
+            }
+            _ => pieces.push((start..span.end, Some(arg.expr()))),
+        }
+        after_flag = is_flag && !text.contains('=');
+    }
+    Some(pieces)
+}
@@ -1,19 +1,42 @@
 //! Closure parameter parsing utilities.
 
+use super::signature::{ParamList, parse_params};
+
+/// Parameters of a closure, like `|x: int, y|`.
+#[derive(Debug, Clone)]
+pub struct ClosureParams<'a> {
+    /// The parameter text as written, including both pipes.
+    pub text: &'a str,
+    /// The parsed parameters, if the list is well-formed and has no comments.
+    pub list: Option<ParamList<'a>>,
+}
+
 /// Parse closure parameters from content after opening brace.
 ///
 /// Returns `(Some(params), rest)` if params like `|x, y|` are found,
 /// otherwise `(None, content)`.
-pub fn parse_closure_params(content: &str) -> (Option<&str>, &str) {
+pub fn parse_closure_params(content: &str) -> (Option<ClosureParams<'_>>, &str) {
     let trimmed = content.trim_start();
-    if !trimmed.starts_with('|') {
+    let Some(inner) = trimmed.strip_prefix('|') else {
         return (None, content);
-    }
+    };
+
+    // Fall back to the next `|` if the list can't be parsed
+    let parsed = parse_params(inner, '|');
+    let close = match &parsed {
+        Some((_, close)) => Some(*close),
+        None => inner.find('|'),
+    };
 
-    trimmed[1..].find('|').map_or((None, content), |close| {
+    close.map_or((None, content), |close| {
         let params_end = close + 2;
-        let params = &trimmed[..params_end];
-        let rest = &trimmed[params_end..];
-        (Some(params), rest)
+        let list = parsed
+            .map(|(list, _)| list)
+            .filter(|list| !list.has_comments());
+        let params = ClosureParams {
+            text: &trimmed[..params_end],
+            list,
+        };
+        (Some(params), &trimmed[params_end..])
     })
 }
@@ -0,0 +1,180 @@
+//! Discovery of `if` / `else` and `try` / `catch` chains.
+
+use std::ops::Range;
+
+use nu_parser::FlatShape;
+use nu_protocol::Span;
+
+use super::token::Token;
+
+/// An `if` or `try` expression with its `else` / `catch` branches.
+#[derive(Debug, Clone)]
+pub struct BranchChain {
+    /// Start offset of the leading `if` or `try`.
+    pub start: usize,
+    /// End offset of the last branch's closing `}`.
+    pub end: usize,
+    /// Each branch, from its opening `{` to the end of its closing `}`.
+    pub branches: Vec<Range<usize>>,
+    /// Whether any branch spans lines or holds more than one statement.
+    pub multiline: bool,
+}
+
+/// Keywords that continue a chain onto another branch.
+const CONTINUATIONS: [&str; 3] = ["else", "catch", "finally"];
+
+/// Collect every `if` and `try` chain whose branches are all brace blocks.
+pub fn collect_chains(tokens: &[Token<'_>]) -> Vec<BranchChain> {
+    tokens
+        .iter()
+        .enumerate()
+        .filter(|(i, token)| {
+            is_call(token, "try") || (is_call(token, "if") && !follows_else(tokens, *i))
+        })
+        .filter_map(|(i, _)| parse_chain(tokens, i))
+        .collect()
+}
+
+/// Parse the chain whose `if` or `try` is at `tokens[index]`.
+fn parse_chain(tokens: &[Token<'_>], index: usize) -> Option<BranchChain> {
+    let start = tokens[index].start;
+    let mut chain = BranchChain {
+        start,
+        end: start,
+        branches: Vec::new(),
+        multiline: false,
+    };
+
+    let mut open = first_branch(tokens, index)?;
+    loop {
+        let close = matching_close(tokens, open)?;
+
+        let open_token = &tokens[open];
+        let branch_start =
+            open_token.start + open_token.text.len() - open_token.text.trim_start().len();
+        chain.end = tokens[close].start + tokens[close].text.trim_end().len();
+        chain.branches.push(branch_start..chain.end);
+        chain.multiline |= tokens[open..=close]
+            .iter()
+            .enumerate()
+            .any(|(i, t)| t.text.contains('\n') || (i > 0 && (t.gap_before.contains(['\n', ';']))));
+
+        let continues = tokens.get(close + 1).is_some_and(|next| {
+            matches!(next.shape, FlatShape::Keyword) && CONTINUATIONS.contains(&next.text)
+        });
+        if !continues {
+            break;
+        }
+        open = match tokens.get(close + 2) {
+            Some(token) if is_call(token, "if") => first_branch(tokens, close + 2)?,
+            Some(token) if is_branch_open(token) => close + 2,
+            _ => return None,
+        };
+    }
+
+    Some(chain)
+}
+
+/// Find the first branch's opening brace after the `if` or `try` at `tokens[index]`.
+fn first_branch(tokens: &[Token<'_>], index: usize) -> Option<usize> {
+    if is_call(&tokens[index], "try") {
+        return tokens
+            .get(index + 1)
+            .filter(|t| is_branch_open(t))
+            .map(|_| index + 1);
+    }
+
+    // Skip the condition, which must not contain blocks of its own
+    let open = (index + 1..tokens.len()).find(|&i| is_branch_open(&tokens[i]))?;
+    let in_condition = &tokens[index + 1..open];
+    (!in_condition.iter().any(is_block_brace) && matches!(tokens[open].shape, FlatShape::Block))
+        .then_some(open)
+}
+
+/// Find the token closing the block opened at `tokens[open]`.
+fn matching_close(tokens: &[Token<'_>], open: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        if !is_block_brace(token) {
+            continue;
+        }
+        let text = token.text.trim();
+        depth += text.chars().filter(|&c| c == '{').count();
+        depth = depth.checked_sub(text.chars().filter(|&c| c == '}').count())?;
+        if depth == 0 {
+            return Some(i);
+        }
+    }
+    None
+}
+
+/// Whether the `if` at `tokens[index]` continues an `else if`.
+fn follows_else(tokens: &[Token<'_>], index: usize) -> bool {
+    index.checked_sub(1).is_some_and(|prev| {
+        matches!(tokens[prev].shape, FlatShape::Keyword) && tokens[prev].text == "else"
+    })
+}
+
+fn is_call(token: &Token<'_>, name: &str) -> bool {
+    matches!(token.shape, FlatShape::InternalCall(_)) && token.text == name
+}
+
+/// Whether the token is part of a `Block` or `Closure` brace pair.
+fn is_block_brace(token: &Token<'_>) -> bool {
+    matches!(token.shape, FlatShape::Block | FlatShape::Closure)
+        && (token.text.trim_start().starts_with('{') || token.text.trim_end().ends_with('}'))
+}
+
+/// Whether the token opens a branch: a block, or a closure for `catch`.
+fn is_branch_open(token: &Token<'_>) -> bool {
+    matches!(token.shape, FlatShape::Block | FlatShape::Closure)
+        && token.text.trim_start().starts_with('{')
+}
+
+/// Join an `else` or `catch` that the source put on the line after its block.
+///
+/// Nushell reads `}` followed by a newline and `else` as the end of the `if`
+/// and a new command named `else`, which is never what the author meant.
+/// Returns the source with the line break replaced by a space, and the new
+/// offsets of each joined keyword so the caller can check that it now parses
+/// as part of the chain.
+pub fn join_detached_keywords(
+    source: &str,
+    flattened: &[(Span, FlatShape)],
+) -> Option<(String, Vec<usize>)> {
+    let mut joined = String::with_capacity(source.len());
+    let mut offsets = Vec::new();
+    let mut last = 0;
+
+    for pair in flattened.windows(2) {
+        let [(prev, prev_shape), (span, shape)] = pair else {
+            continue;
+        };
+        let Some(gap) = source.get(prev.end..span.start) else {
+            continue;
+        };
+        let detached = matches!(prev_shape, FlatShape::Block)
+            && matches!(shape, FlatShape::External(_))
+            && source
+                .get(span.start..span.end)
+                .is_some_and(|text| CONTINUATIONS.contains(&text))
+            && source[prev.start..prev.end].trim_end().ends_with('}')
+            && gap.contains('\n')
+            && gap.trim().is_empty();
+        if !detached {
+            continue;
+        }
+
+        let block_end = prev.start + source[prev.start..prev.end].trim_end().len();
+        joined.push_str(&source[last..block_end]);
+        joined.push(' ');
+        offsets.push(joined.len());
+        last = span.start;
+    }
+
+    if offsets.is_empty() {
+        return None;
+    }
+    joined.push_str(&source[last..]);
+    Some((joined, offsets))
+}
@@ -30,6 +30,13 @@ pub fn is_close_bracket(text: &str) -> bool {
     text == "}" || text == "]"
 }
 
+/// Check if text is an empty collection like `[]` or `{ }`.
+pub fn is_empty_collection(text: &str) -> bool {
+    (text.starts_with('[') && text.ends_with(']') || text.starts_with('{') && text.ends_with('}'))
+        && text.len() >= 2
+        && text[1..text.len() - 1].trim().is_empty()
+}
+
 /// Check if text ends with a closing bracket (brace or square bracket).
 pub fn ends_with_close_bracket(text: &str) -> bool {
     text.ends_with('}') || text.ends_with(']')
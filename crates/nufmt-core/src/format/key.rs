@@ -0,0 +1,150 @@
+//! Quoting of record keys and cell-path members.
+//!
+//! A key flattens into the same `String` token as any other string, so the
+//! keys of each record and the members of each cell path are located from
+//! the parsed AST instead, and quoted together.
+
+use std::collections::HashMap;
+
+use nu_protocol::{
+    ast::{Block, Expr, Expression, PathMember, RecordItem, Traverse},
+    engine::StateWorkingSet,
+};
+
+use super::string::{convert_string_quotes, quote_text};
+use crate::{KeyQuotes, QuoteStyle};
+
+/// Bare words that would parse as something other than a string.
+const RESERVED: [&str; 6] = ["true", "false", "null", "inf", "infinity", "nan"];
+
+/// Render every record key and cell-path member, keyed by start offset.
+///
+/// Record keys that aren't plain strings, like `$"..."` or `(expr)`, are
+/// left out. Under `preserve` only backtick keys are kept, as written, since
+/// `quote_style` would otherwise requote them like any other string.
+pub fn collect_keys(
+    working_set: &StateWorkingSet,
+    block: &Block,
+    source: &str,
+    key_quotes: KeyQuotes,
+    quote_style: QuoteStyle,
+) -> HashMap<usize, String> {
+    let mut found = Vec::new();
+    block.flat_map(
+        working_set,
+        &|expr: &Expression| {
+            let spans: Vec<(usize, usize)> = match &expr.expr {
+                Expr::Record(items) => items
+                    .iter()
+                    .filter_map(|item| match item {
+                        RecordItem::Pair(key, _) if matches!(key.expr, Expr::String(_)) => {
+                            Some((key.span.start, key.span.end))
+                        }
+                        _ => None,
+                    })
+                    .collect(),
+                Expr::FullCellPath(path) => path
+                    .tail
+                    .iter()
+                    .filter_map(|member| match member {
+                        PathMember::String { span, .. } => Some((span.start, span.end)),
+                        PathMember::Int { .. } => None,
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            };
+            let keys: Vec<(usize, Key<'_>)> = spans
+                .into_iter()
+                .filter_map(|(start, end)| Some((start, Key::parse(source.get(start..end)?))))
+                .collect();
+            if key_quotes == KeyQuotes::Preserve {
+                return keys
+                    .iter()
+                    .filter(|(_, key)| key.text.starts_with('`'))
+                    .map(|(start, key)| (*start, key.text.to_string()))
+                    .collect();
+            }
+            render_group(&keys, key_quotes, quote_style)
+        },
+        &mut found,
+    );
+    found.into_iter().collect()
+}
+
+/// A key as written in the source.
+struct Key<'a> {
+    /// The whole key, quotes included.
+    text: &'a str,
+    /// The key between its quotes.
+    content: &'a str,
+    /// Whether the key is quoted.
+    quoted: bool,
+}
+
+impl<'a> Key<'a> {
+    /// Split a key into its quotes and content.
+    fn parse(text: &'a str) -> Self {
+        for quote in ['"', '\'', '`'] {
+            if let Some(content) = text
+                .strip_prefix(quote)
+                .and_then(|rest| rest.strip_suffix(quote))
+            {
+                return Self {
+                    text,
+                    content,
+                    quoted: true,
+                };
+            }
+        }
+        Self {
+            text,
+            content: text,
+            quoted: false,
+        }
+    }
+
+    /// Write the key quoted, converting existing quotes to `style`.
+    ///
+    /// Backticks only quote bare words, so they are replaced too.
+    fn quote(&self, style: QuoteStyle) -> String {
+        if self.quoted && !self.text.starts_with('`') {
+            return convert_string_quotes(self.text, style);
+        }
+        quote_text(self.content, style)
+    }
+}
+
+/// Render the keys of one record or cell path.
+fn render_group(
+    keys: &[(usize, Key<'_>)],
+    key_quotes: KeyQuotes,
+    style: QuoteStyle,
+) -> Vec<(usize, String)> {
+    let all_bare = keys.iter().all(|(_, key)| is_bare_word(key.content));
+    keys.iter()
+        .map(|(start, key)| {
+            let bare = if key_quotes == KeyQuotes::Consistent {
+                all_bare
+            } else {
+                is_bare_word(key.content)
+            };
+            let text = if bare {
+                key.content.to_string()
+            } else {
+                key.quote(style)
+            };
+            (*start, text)
+        })
+        .collect()
+}
+
+/// Whether a key can be written without quotes: a letter or `_` followed by
+/// letters, digits, `_`, and `-`, that isn't a reserved word.
+fn is_bare_word(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        && !RESERVED.contains(&text.to_ascii_lowercase().as_str())
+}
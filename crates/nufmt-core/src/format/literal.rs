@@ -0,0 +1,175 @@
+//! Normalization of number, filesize, and duration literals.
+//!
+//! A filesize or duration flattens into an `Int` token for its number and a
+//! `String` token for its unit, so literals are located from the parsed AST
+//! instead, and rewritten whole.
+
+use std::collections::HashMap;
+
+use nu_protocol::{
+    Span, Type,
+    ast::{Block, Expr, Expression, Traverse},
+    engine::StateWorkingSet,
+};
+
+use crate::{Config, DigitGrouping, NumberCase, UnitCase};
+
+/// Filesize units as Nushell prints them, matched case-insensitively.
+const FILESIZE_UNITS: [&str; 13] = [
+    "B", "kB", "MB", "GB", "TB", "PB", "EB", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB",
+];
+
+/// Spellings of the microsecond unit, the canonical one first.
+const MICROSECOND_UNITS: [&str; 3] = ["\u{00B5}s", "\u{03BC}s", "us"];
+
+/// Integers with fewer digits than this are left ungrouped.
+const GROUPING_THRESHOLD: usize = 5;
+
+/// Collect every literal whose text changes, keyed by start offset, with its
+/// end offset and new text.
+pub fn collect_literals(
+    working_set: &StateWorkingSet,
+    block: &Block,
+    source: &str,
+    config: &Config,
+) -> HashMap<usize, (usize, String)> {
+    if config.number_case == NumberCase::Preserve
+        && config.unit_case == UnitCase::Preserve
+        && config.digit_grouping == DigitGrouping::Preserve
+    {
+        return HashMap::new();
+    }
+    let mut found = Vec::new();
+    block.flat_map(
+        working_set,
+        &|expr: &Expression| literal(expr, source, config).into_iter().collect(),
+        &mut found,
+    );
+
+    // The number of a filesize or duration starts where the whole literal
+    // does, and the latter covers it, so those entries go in last
+    found.sort_by_key(|(_, (end, _)): &(usize, (usize, String))| *end);
+    found.into_iter().collect()
+}
+
+/// Rewrite a single literal, if that changes it.
+fn literal(expr: &Expression, source: &str, config: &Config) -> Option<(usize, (usize, String))> {
+    let (start, end) = (expr.span.start, expr.span.end);
+    let text = source.get(start..end)?;
+    let written = match &expr.expr {
+        Expr::Int(_) | Expr::Float(_) => number(text, config)?,
+        Expr::Binary(_) => binary(text, config.number_case)?,
+        Expr::ValueWithUnit(value) => {
+            let split = value.expr.span.end;
+            if value.expr.span.start != start || value.unit.span != Span::new(split, end) {
+                return None;
+            }
+            let unit = source.get(split..end)?;
+            let unit = match expr.ty {
+                Type::Filesize => filesize_unit(unit, config.unit_case)?,
+                Type::Duration => duration_unit(unit, config.unit_case),
+                _ => return None,
+            };
+            number(source.get(start..split)?, config)? + &unit
+        }
+        _ => return None,
+    };
+    (written != text).then_some((start, (end, written)))
+}
+
+/// Rewrite an integer or float, or `None` if `text` isn't one.
+///
+/// Only `0x`, `0o`, and `0b` in lowercase start a number, so only the
+/// digits after them can change case.
+fn number(text: &str, config: &Config) -> Option<String> {
+    let digits = text.strip_prefix(['-', '+']).unwrap_or(text);
+    let sign = &text[..text.len() - digits.len()];
+    if !digits.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+
+    if ["0x", "0o", "0b"]
+        .iter()
+        .any(|prefix| digits.starts_with(prefix))
+    {
+        let rest = &digits[2..];
+        if !rest.chars().all(|c| c.is_ascii_hexdigit() || c == '_') {
+            return None;
+        }
+        return Some(if config.number_case == NumberCase::Lower {
+            text.to_ascii_lowercase()
+        } else {
+            text.to_string()
+        });
+    }
+
+    if digits.chars().all(|c| c.is_ascii_digit() || c == '_') {
+        return Some(if config.digit_grouping == DigitGrouping::Thousands {
+            format!("{sign}{}", group(digits))
+        } else {
+            text.to_string()
+        });
+    }
+
+    let float = digits
+        .chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, '.' | '_' | 'e' | 'E' | '+' | '-'));
+    float.then(|| {
+        if config.number_case == NumberCase::Lower {
+            text.replace('E', "e")
+        } else {
+            text.to_string()
+        }
+    })
+}
+
+/// Group the digits of a decimal integer in threes from the right, leaving
+/// short ones as written.
+fn group(digits: &str) -> String {
+    let plain: Vec<char> = digits.chars().filter(|&c| c != '_').collect();
+    if plain.len() < GROUPING_THRESHOLD {
+        return digits.to_string();
+    }
+    let mut grouped = String::new();
+    for (i, c) in plain.iter().enumerate() {
+        if i > 0 && (plain.len() - i).is_multiple_of(3) {
+            grouped.push('_');
+        }
+        grouped.push(*c);
+    }
+    grouped
+}
+
+/// Rewrite a binary literal like `0x[FF]`, or `None` if `text` isn't one.
+fn binary(text: &str, case: NumberCase) -> Option<String> {
+    let digits = text.strip_prefix("0x[")?.strip_suffix(']')?;
+    Some(if case == NumberCase::Lower {
+        format!("0x[{}]", digits.to_ascii_lowercase())
+    } else {
+        text.to_string()
+    })
+}
+
+/// Write a filesize unit in `case`.
+fn filesize_unit(unit: &str, case: UnitCase) -> Option<String> {
+    let canonical = FILESIZE_UNITS
+        .iter()
+        .find(|u| u.eq_ignore_ascii_case(unit))?;
+    Some(match case {
+        UnitCase::Preserve => unit.to_string(),
+        UnitCase::Lower => canonical.to_ascii_lowercase(),
+        UnitCase::Canonical => (*canonical).to_string(),
+    })
+}
+
+/// Write a duration unit in `case`.
+///
+/// Nushell only reads duration units in lowercase, so the one spelling that
+/// can change is that of microseconds.
+fn duration_unit(unit: &str, case: UnitCase) -> String {
+    if case == UnitCase::Canonical && MICROSECOND_UNITS.contains(&unit) {
+        MICROSECOND_UNITS[0].to_string()
+    } else {
+        unit.to_string()
+    }
+}
@@ -0,0 +1,250 @@
+//! Discovery and normalization of `match` blocks.
+//!
+//! The flattened tokens of a match block leave its braces, `=>` arrows, and
+//! arm separators in the gaps between tokens, so arms are located from the
+//! parsed AST instead.
+
+use std::ops::Range;
+
+use nu_protocol::{
+    ast::{Block, Expr, Expression, Traverse},
+    engine::StateWorkingSet,
+};
+
+use super::token::Token;
+
+/// A `match` block, located by byte offsets in the source.
+#[derive(Debug, Clone)]
+pub struct MatchBlock {
+    /// Offset of the opening `{`.
+    pub open: usize,
+    /// Offset of the closing `}`.
+    pub close: usize,
+    /// Arms in source order.
+    pub arms: Vec<MatchArm>,
+}
+
+/// A single `pattern if guard => body` arm.
+#[derive(Debug, Clone)]
+pub struct MatchArm {
+    /// Byte range of the pattern.
+    pub pattern: Range<usize>,
+    /// Byte range of the guard expression after `if`.
+    pub guard: Option<Range<usize>>,
+    /// Byte range of the body after `=>`.
+    pub body: Range<usize>,
+}
+
+impl MatchArm {
+    /// Render the arm's pattern and guard, without the arrow.
+    pub fn head(&self, source: &str, spaced: bool) -> String {
+        let mut head = normalize_pattern(&source[self.pattern.clone()], spaced);
+        if let Some(guard) = &self.guard {
+            head.push_str(" if ");
+            head.push_str(&collapse_whitespace(&source[guard.clone()]));
+        }
+        head
+    }
+}
+
+/// Collect every well-formed `match` block in the parsed source.
+///
+/// Blocks with comments between a pattern and its body are skipped so they
+/// can be left as written.
+pub fn collect_match_blocks(
+    working_set: &StateWorkingSet,
+    block: &Block,
+    source: &str,
+) -> Vec<MatchBlock> {
+    let mut found = Vec::new();
+    block.flat_map(
+        working_set,
+        &|expr: &Expression| match_block(expr, source).into_iter().collect(),
+        &mut found,
+    );
+    found.sort_by_key(|m: &MatchBlock| m.open);
+    found
+}
+
+/// Keep only match blocks whose arms line up with token boundaries.
+///
+/// Also returns the index of the first token of each arm's body, per block.
+pub fn retain_token_arms(
+    matches: Vec<MatchBlock>,
+    tokens: &[Token<'_>],
+) -> (Vec<MatchBlock>, Vec<Vec<usize>>) {
+    matches
+        .into_iter()
+        .filter_map(|m| {
+            let bodies = m
+                .arms
+                .iter()
+                .map(|arm| {
+                    tokens.iter().position(|t| t.start == arm.pattern.start)?;
+                    tokens
+                        .iter()
+                        .position(|t| t.start >= arm.body.start)
+                        .filter(|&i| tokens[i].start < arm.body.end)
+                })
+                .collect::<Option<Vec<_>>>()?;
+            Some((m, bodies))
+        })
+        .unzip()
+}
+
+/// Build a `MatchBlock` from a `match` block expression.
+fn match_block(expr: &Expression, source: &str) -> Option<MatchBlock> {
+    let Expr::MatchBlock(arms) = &expr.expr else {
+        return None;
+    };
+    let span = expr.span;
+    if arms.is_empty()
+        || span.end <= span.start
+        || source.get(span.start..=span.start) != Some("{")
+        || source.get(span.end - 1..span.end) != Some("}")
+    {
+        return None;
+    }
+    let (open, close) = (span.start, span.end - 1);
+
+    let arms = arms
+        .iter()
+        .map(|(pattern, body)| {
+            let guard = pattern.guard.as_ref().map(|g| g.span.start..g.span.end);
+            // The pattern's span may run on into its guard
+            let pattern_end = match &guard {
+                Some(g) => {
+                    let before_guard = source.get(pattern.span.start..g.start)?.trim_end();
+                    pattern.span.start + before_guard.strip_suffix("if")?.trim_end().len()
+                }
+                None => pattern.span.end,
+            };
+            let arm = MatchArm {
+                pattern: pattern.span.start..pattern_end,
+                guard,
+                body: body.span.start..body.span.end,
+            };
+
+            let head_end = arm.guard.as_ref().map_or(pattern_end, |g| g.end);
+            let valid = open < arm.pattern.start
+                && arm.pattern.start < pattern_end
+                && head_end <= arm.body.start
+                && arm.body.end <= close
+                && source[head_end..arm.body.start].trim() == "=>"
+                && !source[arm.pattern.start..arm.body.start].contains('#');
+            valid.then_some(arm)
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(MatchBlock { open, close, arms })
+}
+
+/// Normalize spacing in a match pattern.
+///
+/// Whitespace is collapsed, `,` and `:` are followed by one space, `|`
+/// between alternatives gets a space on each side, and non-empty record and
+/// list patterns follow the bracket spacing setting. Quoted text is copied
+/// as written.
+pub fn normalize_pattern(pattern: &str, spaced: bool) -> String {
+    let mut out = String::with_capacity(pattern.len());
+    let mut chars = pattern.trim().chars();
+    let mut pending_space = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\'' | '`' => {
+                if pending_space && !out.is_empty() && !out.ends_with([' ', '[', '{']) {
+                    out.push(' ');
+                }
+                out.push(c);
+                let mut escaped = false;
+                for q in chars.by_ref() {
+                    out.push(q);
+                    if escaped {
+                        escaped = false;
+                    } else if q == '\\' && c == '"' {
+                        escaped = true;
+                    } else if q == c {
+                        break;
+                    }
+                }
+                pending_space = false;
+                continue;
+            }
+            c if c.is_whitespace() => {
+                pending_space = true;
+                continue;
+            }
+            ',' | ':' => {
+                trim_space(&mut out);
+                out.push(c);
+                out.push(' ');
+            }
+            '|' => {
+                trim_space(&mut out);
+                out.push_str(" | ");
+            }
+            '[' | '{' => {
+                if pending_space && !out.is_empty() && !out.ends_with([' ', '[', '{']) {
+                    out.push(' ');
+                }
+                out.push(c);
+                if spaced {
+                    out.push(' ');
+                }
+            }
+            ']' | '}' => {
+                trim_space(&mut out);
+                if spaced && !out.ends_with(['[', '{']) {
+                    out.push(' ');
+                }
+                out.push(c);
+            }
+            _ => {
+                if pending_space && !out.is_empty() && !out.ends_with([' ', '[', '{']) {
+                    out.push(' ');
+                }
+                out.push(c);
+            }
+        }
+        pending_space = false;
+    }
+
+    out.trim_end().to_string()
+}
+
+/// Drop trailing spaces from `out`.
+fn trim_space(out: &mut String) {
+    out.truncate(out.trim_end_matches(' ').len());
+}
+
+/// Collapse runs of whitespace outside quotes to single spaces.
+fn collapse_whitespace(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut quote = None;
+    let mut pending_space = false;
+
+    for c in text.trim().chars() {
+        if let Some(q) = quote {
+            out.push(c);
+            if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        if c.is_whitespace() {
+            pending_space = true;
+            continue;
+        }
+        if pending_space {
+            out.push(' ');
+            pending_space = false;
+        }
+        if matches!(c, '"' | '\'' | '`') {
+            quote = Some(c);
+        }
+        out.push(c);
+    }
+
+    out
+}
@@ -4,15 +4,31 @@
 //! automatically chooses between single-line and multiline layouts based on the
 //! configured `max_width`.
 
+mod block;
+mod boolean;
+mod call;
 mod closure;
+mod conditional;
 mod delim;
 mod error;
+mod key;
+mod literal;
+mod match_block;
+mod pipeline;
+mod record;
+mod redirection;
+mod signature;
 mod string;
+mod table;
 mod token;
+mod wrap;
 
 pub use error::{FormatError, SourceLocation};
 
-use std::sync::LazyLock;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::LazyLock,
+};
 
 use nu_cmd_lang::create_default_context;
 use nu_command::add_shell_command_context;
@@ -22,15 +38,33 @@ use nu_protocol::{
     engine::{EngineState, StateWorkingSet},
 };
 use pretty::{Arena, DocAllocator, DocBuilder};
+use unicode_width::UnicodeWidthStr;
 
-use crate::{BracketSpacing, Config, TrailingComma};
-use closure::parse_closure_params;
+use crate::{
+    BlockLayout, BracketSpacing, CollectionLayout, Config, FlagValueStyle, PipePosition,
+    TrailingComma,
+};
+use block::{BraceBlock, collapsed_width, collect_blocks, later_statements};
+use boolean::collect_conditions;
+use call::{EnvPrefix, collect_calls, collect_env_prefixes, collect_flag_values};
+use closure::{ClosureParams, parse_closure_params};
+use conditional::{BranchChain, collect_chains, join_detached_keywords};
 use delim::{
     count_close_braces, ends_with_close_brace, ends_with_close_bracket, ends_with_close_paren,
-    is_close_bracket, is_open_bracket, starts_with_open_brace, starts_with_open_paren,
+    is_close_bracket, is_empty_collection, is_open_bracket, starts_with_open_brace,
+    starts_with_open_paren,
 };
-use string::convert_string_quotes;
+use key::collect_keys;
+use literal::collect_literals;
+use match_block::{MatchBlock, collect_match_blocks, retain_token_arms};
+use pipeline::{Pipeline, collect_pipelines, flat_width};
+use record::collect_key_padding;
+use redirection::normalize_redirection;
+use signature::{IoTypes, Param, ParamList, parse_signature};
+use string::{collect_interpolations, convert_string_quotes};
+use table::{TableLiteral, collect_tables, column_widths, render_row, retain_token_tables};
 use token::{Token, preprocess_tokens};
+use wrap::Wrap;
 
 /// Type alias for our document builder.
 type Doc<'a> = DocBuilder<'a, Arena<'a>>;
@@ -42,6 +76,9 @@ static ENGINE_STATE: LazyLock<EngineState> = LazyLock::new(|| {
     add_shell_command_context(engine_state)
 });
 
+/// Operators written without spaces around them.
+const RANGE_OPERATORS: [&str; 3] = ["..", "..=", "..<"];
+
 /// Check if a parse error is a resolution error (module/file/command not found).
 const fn is_resolution_error(error: &ParseError) -> bool {
     matches!(
@@ -123,19 +160,104 @@ pub fn format_source(source: &str, config: &Config) -> Result<String, FormatErro
     }
 
     let flattened = flatten_block(&working_set, &block);
-    let formatted = format_tokens(source, &flattened, config);
+
+    // Reattach `else` or `catch` written on the line after its block, as long
+    // as that turns it back into part of the `if` or `try`
+    if let Some((joined, keywords)) = join_detached_keywords(source, &flattened)
+        && parses_as_keywords(&joined, &keywords)
+    {
+        return format_source(&joined, config);
+    }
+
+    let env_prefixes = collect_env_prefixes(&working_set, &block, source);
+    let keys = collect_keys(
+        &working_set,
+        &block,
+        source,
+        config.key_quotes,
+        config.quote_style,
+    );
+    // A condition starting with a parenthesized call wins over the call
+    let syntax = Syntax {
+        pipelines: collect_pipelines(&ENGINE_STATE, &working_set, &block, source),
+        matches: collect_match_blocks(&working_set, &block, source),
+        tables: collect_tables(&working_set, &block, source),
+        key_padding: collect_key_padding(&working_set, &block, source, &keys),
+        blocks: collect_blocks(&working_set, &block, source, config.block_layout),
+        wraps: collect_calls(&working_set, &block, source, &env_prefixes)
+            .into_iter()
+            .chain(collect_conditions(&working_set, &block, source))
+            .collect(),
+        env_prefixes,
+        flag_values: collect_flag_values(&working_set, &block, source),
+        keys,
+        interpolations: collect_interpolations(&working_set, &block, source, config.quote_style),
+        literals: collect_literals(&working_set, &block, source, config),
+    };
+    let formatted = format_tokens(source, &flattened, syntax, config);
     Ok(formatted)
 }
 
+/// Check that `source` parses cleanly with a keyword at each offset.
+fn parses_as_keywords(source: &str, offsets: &[usize]) -> bool {
+    let mut working_set = StateWorkingSet::new(&ENGINE_STATE);
+    let block = parse(&mut working_set, None, source.as_bytes(), false);
+    if working_set
+        .parse_errors
+        .iter()
+        .any(|e| !is_resolution_error(e))
+    {
+        return false;
+    }
+
+    let flattened = flatten_block(&working_set, &block);
+    offsets.iter().all(|&offset| {
+        flattened
+            .iter()
+            .any(|(span, shape)| span.start == offset && matches!(shape, FlatShape::Keyword))
+    })
+}
+
+/// Constructs located in the parsed AST that the token stream can't reveal.
+struct Syntax {
+    /// Multi-stage pipelines, ordered by start offset.
+    pipelines: Vec<Pipeline>,
+    /// `match` blocks, ordered by opening brace.
+    matches: Vec<MatchBlock>,
+    /// Table literals, ordered by opening bracket.
+    tables: Vec<TableLiteral>,
+    /// Padding after each record key, keyed by the key's end offset.
+    key_padding: HashMap<usize, usize>,
+    /// Brace blocks, keyed by opening brace offset.
+    blocks: HashMap<usize, BraceBlock>,
+    /// Environment prefixes of commands, keyed by start offset.
+    env_prefixes: HashMap<usize, EnvPrefix>,
+    /// Start offsets of the values of long flags that take one.
+    flag_values: HashSet<usize>,
+    /// Conditions and command calls that can be broken inside parentheses,
+    /// keyed by start offset.
+    wraps: HashMap<usize, Wrap>,
+    /// Record keys and cell-path members as they should be quoted, keyed by
+    /// start offset.
+    keys: HashMap<usize, String>,
+    /// String interpolations whose quotes change, keyed by start offset, with
+    /// their end offset and new text.
+    interpolations: HashMap<usize, (usize, String)>,
+    /// Number, filesize, and duration literals that change, keyed by start
+    /// offset, with their end offset and new text.
+    literals: HashMap<usize, (usize, String)>,
+}
+
 /// Format tokens into a string using the pretty printing algorithm.
 fn format_tokens(
     source: &str,
     flattened: &[(nu_protocol::Span, FlatShape)],
+    syntax: Syntax,
     config: &Config,
 ) -> String {
     let tokens = preprocess_tokens(source, flattened);
     let arena = Arena::new();
-    let mut formatter = Formatter::new(&arena, &tokens, config);
+    let mut formatter = Formatter::new(&arena, source, &tokens, syntax, config);
     let doc = formatter.format_all();
 
     let mut output = String::new();
@@ -149,6 +271,28 @@ fn format_tokens(
     output
 }
 
+/// What lies between a collection's brackets, as far as its layout goes.
+struct CollectionContents {
+    /// Whether a comment appears anywhere inside.
+    comments: bool,
+    /// Whether anything other than separators spans lines.
+    multiline_items: bool,
+    /// Width of the collection laid out on one line.
+    flat_width: usize,
+    /// Width of each item, if every item is a single token.
+    simple_items: Option<Vec<usize>>,
+}
+
+/// A multiline list with as many items packed onto each line as fit.
+struct Fill {
+    /// Width of each item.
+    widths: Vec<usize>,
+    /// Index of the item most recently emitted.
+    item: usize,
+    /// Column after the item most recently emitted.
+    column: usize,
+}
+
 /// The formatter state.
 ///
 /// Note on `indent_level`: We track indentation manually rather than using the
@@ -158,6 +302,7 @@ fn format_tokens(
 /// at that exact point, which doesn't integrate well with `nest()`'s automatic behavior.
 struct Formatter<'a> {
     arena: &'a Arena<'a>,
+    source: &'a str,
     tokens: &'a [Token<'a>],
     config: &'a Config,
     index: usize,
@@ -169,19 +314,188 @@ struct Formatter<'a> {
     /// Stack tracking if each nested collection is multiline.
     /// Pushed on opening bracket, popped on closing bracket.
     multiline_stack: Vec<bool>,
+    /// Packing state of the list being filled, if any.
+    fill: Option<Fill>,
+    /// End offset of the last `def` signature and the output column it ends at.
+    signature_end: Option<(usize, usize)>,
+    /// Output columns of offsets on the current output line: where each token
+    /// emitted on it starts and ends, or where the next token starts after a
+    /// line break. Cleared at every line break.
+    line_marks: Vec<(usize, usize)>,
+    /// Stack tracking if each open brace block was broken across lines.
+    /// Pushed on `{`, popped on the matching `}`.
+    block_stack: Vec<bool>,
+    /// Multi-stage pipelines in the source, ordered by start offset.
+    pipelines: Vec<Pipeline>,
+    /// Maps each pipe's source offset to the index of its pipeline.
+    pipe_owners: HashMap<usize, usize>,
+    /// Whether each pipeline is broken across lines, decided at its first pipe.
+    pipeline_broken: Vec<Option<bool>>,
+    /// Indices of broken pipelines whose continuation indent is active.
+    open_broken_pipelines: Vec<usize>,
+    /// Set when the next token's gap was already emitted by the previous token.
+    skip_next_gap: bool,
+    /// Set after an operator: whether the next token is separated from it by a space.
+    after_operator: Option<bool>,
+    /// `if` / `else` and `try` / `catch` chains in the source.
+    chains: Vec<BranchChain>,
+    /// Maps each branch's opening brace offset to the index of its chain.
+    branch_owners: HashMap<usize, usize>,
+    /// Whether each chain's branches are broken across lines, decided at its first branch.
+    chain_broken: Vec<Option<bool>>,
+    /// `match` blocks whose arms are laid out by the formatter.
+    matches: Vec<MatchBlock>,
+    /// Rendered pattern and guard of each arm, per match block.
+    match_heads: Vec<Vec<String>>,
+    /// Maps each arm's pattern offset to its match and arm indices.
+    arm_owners: HashMap<usize, (usize, usize)>,
+    /// Index of the first token of each arm's body, per match block.
+    arm_bodies: Vec<Vec<usize>>,
+    /// Indices of match blocks whose closing `}` hasn't been emitted yet.
+    open_matches: Vec<usize>,
+    /// Whether each match block has one arm per line, decided at its first arm.
+    match_broken: Vec<bool>,
+    /// Table literals laid out by the formatter.
+    tables: Vec<TableLiteral>,
+    /// Rendered cells of each row, per table.
+    table_cells: Vec<Vec<Vec<String>>>,
+    /// Maps each table's opening bracket offset to its index.
+    table_owners: HashMap<usize, usize>,
+    /// Padding after each record key, keyed by the key's end offset.
+    key_padding: HashMap<usize, usize>,
+    /// Brace blocks, keyed by opening brace offset.
+    blocks: HashMap<usize, BraceBlock>,
+    /// Start offsets of every statement after the first in a brace block,
+    /// mapped to the block's opening brace offset.
+    later_statements: HashMap<usize, usize>,
+    /// Environment prefixes of commands, keyed by start offset.
+    env_prefixes: HashMap<usize, EnvPrefix>,
+    /// Start offsets of the values of long flags that take one.
+    flag_values: HashSet<usize>,
+    /// Conditions and command calls that can be broken inside parentheses,
+    /// keyed by start offset.
+    wraps: HashMap<usize, Wrap>,
+    /// Start offsets of the broken wraps whose `)` hasn't been emitted yet.
+    open_wraps: Vec<usize>,
+    /// Record keys and cell-path members as they should be quoted, keyed by
+    /// start offset.
+    keys: HashMap<usize, String>,
+    /// String interpolations whose quotes change, keyed by start offset, with
+    /// their end offset and new text.
+    interpolations: HashMap<usize, (usize, String)>,
+    /// Number, filesize, and duration literals that change, keyed by start
+    /// offset, with their end offset and new text.
+    literals: HashMap<usize, (usize, String)>,
 }
 
 impl<'a> Formatter<'a> {
-    #[allow(clippy::missing_const_for_fn)] // Vec::new() in const requires nightly
-    fn new(arena: &'a Arena<'a>, tokens: &'a [Token<'a>], config: &'a Config) -> Self {
+    fn new(
+        arena: &'a Arena<'a>,
+        source: &'a str,
+        tokens: &'a [Token<'a>],
+        syntax: Syntax,
+        config: &'a Config,
+    ) -> Self {
+        let Syntax {
+            pipelines,
+            matches,
+            tables,
+            key_padding,
+            blocks,
+            env_prefixes,
+            flag_values,
+            wraps,
+            keys,
+            interpolations,
+            literals,
+        } = syntax;
+
+        let pipe_owners = pipelines
+            .iter()
+            .enumerate()
+            .flat_map(|(i, p)| p.pipes.iter().map(move |&pipe| (pipe, i)))
+            .collect();
+        let pipeline_broken = vec![None; pipelines.len()];
+
+        let chains = collect_chains(tokens);
+        let branch_owners = chains
+            .iter()
+            .enumerate()
+            .flat_map(|(i, c)| c.branches.iter().map(move |branch| (branch.start, i)))
+            .collect();
+        let chain_broken = vec![None; chains.len()];
+
+        let (matches, arm_bodies) = retain_token_arms(matches, tokens);
+        let spaced = config.bracket_spacing == BracketSpacing::Spaced;
+        let match_heads = matches
+            .iter()
+            .map(|m| m.arms.iter().map(|arm| arm.head(source, spaced)).collect())
+            .collect();
+        let match_broken = vec![false; matches.len()];
+        let arm_owners = matches
+            .iter()
+            .enumerate()
+            .flat_map(|(i, m)| {
+                m.arms
+                    .iter()
+                    .enumerate()
+                    .map(move |(j, arm)| (arm.pattern.start, (i, j)))
+            })
+            .collect();
+
+        let tables = retain_token_tables(tables, tokens);
+        let table_cells = tables
+            .iter()
+            .map(|t| t.cells(source, tokens, &literals, config.quote_style))
+            .collect();
+        let table_owners = tables
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (t.open, i))
+            .collect();
+        let later_statements = later_statements(&blocks);
+
         Self {
             arena,
+            source,
             tokens,
             config,
             index: 0,
             interp_depth: 0,
             indent_level: 0,
             multiline_stack: Vec::new(),
+            fill: None,
+            signature_end: None,
+            line_marks: Vec::new(),
+            block_stack: Vec::new(),
+            pipelines,
+            pipe_owners,
+            pipeline_broken,
+            open_broken_pipelines: Vec::new(),
+            skip_next_gap: false,
+            after_operator: None,
+            chains,
+            branch_owners,
+            chain_broken,
+            matches,
+            match_heads,
+            arm_owners,
+            arm_bodies,
+            open_matches: Vec::new(),
+            match_broken,
+            tables,
+            table_cells,
+            table_owners,
+            key_padding,
+            blocks,
+            later_statements,
+            env_prefixes,
+            flag_values,
+            wraps,
+            open_wraps: Vec::new(),
+            keys,
+            interpolations,
+            literals,
         }
     }
 
@@ -195,22 +509,602 @@ impl<'a> Formatter<'a> {
         let mut docs: Vec<Doc<'a>> = Vec::new();
 
         while self.index < self.tokens.len() {
+            let first = self.index;
             let doc = self.format_next();
+            self.track_columns(first, &doc);
             docs.push(doc);
         }
+        docs.push(self.close_wraps(None).0);
 
         self.arena.concat(docs)
     }
 
+    /// Record the output columns of the tokens from index `first` on, which
+    /// `doc` has just laid out.
+    fn track_columns(&mut self, first: usize, doc: &Doc<'a>) {
+        let mut text = String::new();
+        doc.render_fmt(self.config.max_width, &mut text).unwrap();
+        let token = &self.tokens[first];
+        let last = &self.tokens[self.index - 1];
+        let end = last.start + last.text.len();
+
+        let Some((_, line)) = text.rsplit_once('\n') else {
+            let column = self.line_marks.last().map_or(0, |&(_, column)| column) + text.width();
+            if first + 1 == self.index && text.ends_with(token.text) {
+                self.line_marks
+                    .push((token.start, column - token.text.width()));
+            }
+            self.line_marks.push((end, column));
+            return;
+        };
+
+        self.line_marks.clear();
+        let content = line.trim_start();
+        let indent = line.len() - content.len();
+        if content.is_empty() {
+            // The line break comes after the tokens, so the next one starts the line
+            if let Some(next) = self.tokens.get(self.index) {
+                self.line_marks.push((next.start, indent));
+            }
+        } else {
+            if content.starts_with(token.text) {
+                self.line_marks.push((token.start, indent));
+            }
+            self.line_marks.push((end, line.width()));
+        }
+    }
+
+    /// Output column of the token at `offset`, measured from the tokens
+    /// already emitted on the current output line.
+    ///
+    /// Returns `None` if nothing on that line precedes `offset`, or a line
+    /// break lies between them.
+    fn output_column(&self, offset: usize) -> Option<usize> {
+        let &(mark, column) = self.line_marks.iter().rev().find(|&&(o, _)| o <= offset)?;
+        let (line_start, _) = self.output_line_start(offset);
+        if mark < line_start {
+            return None;
+        }
+        let between = &self.source[mark..offset];
+        let (width, _) = flat_width(between);
+        let leading = usize::from(between.starts_with(char::is_whitespace));
+        let trailing = usize::from(width > 0 && between.ends_with(char::is_whitespace));
+        Some(column + leading + width + trailing)
+    }
+
     /// Format the next token and its preceding gap.
     fn format_next(&mut self) -> Doc<'a> {
-        let token = &self.tokens[self.index].clone();
+        let mut token = self.tokens[self.index].clone();
         self.index += 1;
+        let after_operator = self.after_operator.take();
+
+        // Wraps close first, as they sit inside pipelines and matches
+        let (mut prefix, consumed) = self.close_wraps(Some(&mut token));
+        if consumed {
+            return prefix;
+        }
+
+        self.close_broken_pipelines(token.start);
+
+        // Closing braces of match blocks live in gaps or in later tokens
+        if self.closes_match(&token) {
+            let (doc, rest) = self.close_matches(&token);
+            prefix = prefix.append(doc);
+            match rest {
+                Some(rest) => token = rest,
+                None => return prefix,
+            }
+        }
+
+        if self
+            .open_wraps
+            .last()
+            .is_some_and(|start| self.wraps[start].breaks.contains(&token.start))
+        {
+            // The gap can hold the `?` of an optional cell path
+            prefix = prefix
+                .append(self.arena.text(token.gap_before.trim_end()))
+                .append(self.arena.hardline())
+                .append(self.arena.text(self.indent_str()));
+            self.skip_next_gap = true;
+        }
+
+        if let Some(doc) = self.open_wrap(&token) {
+            let parenthesized = self.wraps[&token.start].parenthesized;
+            prefix = prefix.append(doc);
+            if parenthesized {
+                return prefix;
+            }
+        }
+
+        if let Some(&(m, arm)) = self.arm_owners.get(&token.start) {
+            return prefix.append(self.format_match_arm(m, arm, token.gap_before));
+        }
+
+        if matches!(token.shape, FlatShape::Table)
+            && let Some(&table) = self.table_owners.get(&token.start)
+        {
+            return prefix.append(self.format_table(table, token.gap_before));
+        }
+
+        if matches!(token.shape, FlatShape::Pipe) && self.is_broken_pipe(&token) {
+            return prefix.append(self.format_broken_pipe(&token));
+        }
+
+        if matches!(token.shape, FlatShape::Redirection) {
+            return prefix.append(self.format_redirection(&token));
+        }
+
+        if matches!(token.shape, FlatShape::Operator) {
+            return prefix.append(self.format_operator(&token));
+        }
+
+        if let Some(env) = self.env_prefixes.get(&token.start) {
+            let (end, text) = (env.end(), env.render(self.source));
+            return prefix.append(self.format_verbatim(text, end, token.gap_before));
+        }
+
+        // An interpolation nested in another's expression keeps its quotes
+        if let Some((end, text)) = self
+            .interpolations
+            .get(&token.start)
+            .filter(|_| self.interp_depth == 0)
+            .or_else(|| self.literals.get(&token.start))
+        {
+            let (end, text) = (*end, text.clone());
+            return prefix.append(self.format_verbatim(text, end, token.gap_before));
+        }
+
+        let gap_doc = if std::mem::take(&mut self.skip_next_gap) {
+            self.arena.nil()
+        } else if self.flag_values.contains(&token.start) && !token.gap_before.contains('\n') {
+            self.format_flag_value_gap(token.gap_before)
+        } else if let Some(spaced) = after_operator
+            && is_inline_space(token.gap_before)
+        {
+            if spaced {
+                self.arena.space()
+            } else {
+                self.arena.nil()
+            }
+        } else if self.starts_expanded_statement(&token) {
+            // Statements that shared a line go on their own lines once the
+            // block is expanded
+            self.arena
+                .hardline()
+                .append(self.arena.text(self.indent_str()))
+        } else {
+            self.format_gap(token.gap_before)
+        };
+        let token_doc = self.format_token(&token);
+
+        prefix.append(gap_doc).append(token_doc)
+    }
+
+    /// Emit `text` in place of the tokens up to `end`, and skip past them.
+    fn format_verbatim(&mut self, text: String, end: usize, gap: &'a str) -> Doc<'a> {
+        let gap_doc = if std::mem::take(&mut self.skip_next_gap) {
+            self.arena.nil()
+        } else {
+            self.format_gap(gap)
+        };
+        self.index = self
+            .tokens
+            .iter()
+            .position(|t| t.start >= end)
+            .unwrap_or(self.tokens.len());
+        let mut doc = gap_doc.append(self.arena.text(text));
+
+        // The next token's gap may start inside the replaced text
+        if let Some(next) = self.tokens.get(self.index)
+            && next.start - next.gap_before.len() < end
+        {
+            let rest = &self.source[end..next.start];
+            doc = doc.append(self.format_gap(rest));
+            self.skip_next_gap = true;
+        }
+        doc
+    }
+
+    /// Join a long flag to its value according to `flag_value_style`.
+    fn format_flag_value_gap(&self, gap: &'a str) -> Doc<'a> {
+        let equals = match self.config.flag_value_style {
+            FlagValueStyle::Preserve => gap == "=",
+            FlagValueStyle::Equals => true,
+            FlagValueStyle::Space => false,
+        };
+        if equals {
+            self.arena.text("=")
+        } else {
+            self.arena.space()
+        }
+    }
+
+    /// Open a wrap broken into pieces, if `token` starts one.
+    ///
+    /// A wrap is broken if it doesn't fit in `max_width` or was already
+    /// multiline. It is then wrapped in parentheses, which Nushell requires
+    /// for the line breaks, with each piece on its own line at one more level
+    /// of indentation. An existing `(` is `token` itself.
+    fn open_wrap(&mut self, token: &Token<'a>) -> Option<Doc<'a>> {
+        let wrap = self.wraps.get(&token.start)?;
+        // A block kept on one line can't hold a broken wrap
+        if self.block_stack.last() == Some(&false) {
+            return None;
+        }
+        let text = &self.source[wrap.start..wrap.measure_end];
+        let broken = wrap.multiline
+            || self.line_prefix_width(wrap.start) + collapsed_width(text) + wrap.trailing_width
+                > self.config.max_width;
+        if !broken {
+            return None;
+        }
+
+        let hug_open = wrap.hug_open;
+        let gap = if std::mem::take(&mut self.skip_next_gap) {
+            self.arena.nil()
+        } else {
+            self.format_gap(token.gap_before)
+        };
+        self.open_wraps.push(token.start);
+        self.indent_level += 1;
+        self.skip_next_gap = true;
+        let doc = gap.append(self.arena.text("("));
+        Some(if hug_open {
+            doc
+        } else {
+            doc.append(self.arena.hardline())
+                .append(self.arena.text(self.indent_str()))
+        })
+    }
+
+    /// Close the broken wraps that end before `token`, or all of them at the
+    /// end of input, also returning whether `token` was the `)` of one.
+    ///
+    /// Text before the `)` that belongs to a wrap, like the `?` of
+    /// `$x.name?`, is emitted ahead of it and dropped from the token's gap.
+    fn close_wraps(&mut self, mut token: Option<&mut Token<'a>>) -> (Doc<'a>, bool) {
+        let mut docs = Vec::new();
+        while let Some(open) = self.open_wraps.last() {
+            let wrap = &self.wraps[open];
+            let (parenthesized, end) = (wrap.parenthesized, wrap.end);
+            let mut tail = "";
+            if let Some(token) = token.as_deref_mut() {
+                let token_end = token.start + token.text.len();
+                if parenthesized && token_end < end || !parenthesized && token.start < end {
+                    break;
+                }
+                let gap_start = token.start - token.gap_before.len();
+                if parenthesized {
+                    tail = &self.source[gap_start..end - ")".len()];
+                } else if gap_start < end {
+                    tail = &self.source[gap_start..end];
+                    token.gap_before = &self.source[end..token.start];
+                }
+            }
+            self.open_wraps.pop();
+            self.indent_level = self.indent_level.saturating_sub(1);
+            docs.push(self.arena.text(tail.trim_end()));
+            docs.push(self.arena.hardline());
+            docs.push(self.arena.text(self.indent_str()));
+            docs.push(self.arena.text(")"));
+            if parenthesized {
+                return (self.arena.concat(docs), true);
+            }
+        }
+        (self.arena.concat(docs), false)
+    }
+
+    /// Format a redirection like `o> file` or `e>| cmd` with one space on
+    /// each side, spelled according to `redirection_style`.
+    fn format_redirection(&mut self, token: &Token<'a>) -> Doc<'a> {
+        let gap_doc = if std::mem::take(&mut self.skip_next_gap) {
+            self.arena.nil()
+        } else if is_inline_space(token.gap_before) {
+            self.arena.space()
+        } else {
+            self.format_gap(token.gap_before)
+        };
+        self.after_operator = Some(true);
+        gap_doc.append(self.arena.text(normalize_redirection(
+            token.text,
+            self.config.redirection_style,
+        )))
+    }
+
+    /// Format an operator token and the space before it.
+    ///
+    /// Binary operators, word operators like `and` and `not-in` included, get
+    /// exactly one space on each side, while range operators like `..` and
+    /// `..=` get none. Prefix `not` and `...` keep whatever precedes them and
+    /// are followed by a space and nothing, respectively. Line breaks and
+    /// comments around an operator are kept.
+    fn format_operator(&mut self, token: &Token<'a>) -> Doc<'a> {
+        let (space_before, space_after) = match token.text {
+            text if RANGE_OPERATORS.contains(&text) => (Some(false), false),
+            "not" => (None, true),
+            "..." => (None, false),
+            _ => (Some(true), true),
+        };
+        let gap_doc = if std::mem::take(&mut self.skip_next_gap) {
+            self.arena.nil()
+        } else {
+            match space_before {
+                Some(true) if is_inline_space(token.gap_before) => self.arena.space(),
+                Some(false) if is_inline_space(token.gap_before) => self.arena.nil(),
+                _ => self.format_gap(token.gap_before),
+            }
+        };
+        self.after_operator = Some(space_after);
+        gap_doc.append(self.format_token(token))
+    }
+
+    /// Check whether `token` starts a statement that follows another on the
+    /// same line, in a block that `block_layout` expanded.
+    ///
+    /// Under `block_layout = "preserve"` that is only the case in a branch
+    /// of a broken chain whose statements all shared one line.
+    fn starts_expanded_statement(&self, token: &Token<'a>) -> bool {
+        let Some(open) = self.later_statements.get(&token.start) else {
+            return false;
+        };
+        let expanded = self.config.block_layout != BlockLayout::Preserve
+            || self.branch_owners.contains_key(open)
+                && self.blocks.get(open).is_some_and(|b| !b.multiline);
+        expanded
+            && self.block_stack.last() == Some(&true)
+            && !token.gap_before.contains(['\n', '#'])
+    }
+
+    /// Check whether the innermost open match block closes before the end of `token`.
+    fn closes_match(&self, token: &Token<'a>) -> bool {
+        self.open_matches
+            .last()
+            .is_some_and(|&m| self.matches[m].close < token.start + token.text.len())
+    }
+
+    /// Emit the closing `}` of every match block that ends in `token` or its gap.
+    ///
+    /// Returns the closing docs and whatever is left of the token afterwards,
+    /// or `None` if nothing but whitespace remains.
+    fn close_matches(&mut self, token: &Token<'a>) -> (Doc<'a>, Option<Token<'a>>) {
+        let token_end = token.start + token.text.len();
+        let mut cursor = token.start - token.gap_before.len();
+        let mut docs = Vec::new();
+
+        while let Some(&m) = self.open_matches.last()
+            && self.matches[m].close < token_end
+        {
+            let close = self.matches[m].close;
+            self.open_matches.pop();
+            self.indent_level = self.indent_level.saturating_sub(1);
+            if self.match_broken[m] {
+                let (comments, _) = self.format_gap_comments(&self.source[cursor..close], false);
+                docs.push(comments);
+                docs.push(self.arena.hardline());
+                docs.push(self.arena.text(self.indent_str()));
+                docs.push(self.arena.text("}"));
+            } else {
+                docs.push(self.arena.text(" }"));
+            }
+            cursor = close + 1;
+        }
+
+        let rest = if cursor <= token.start {
+            Some(Token {
+                gap_before: &self.source[cursor..token.start],
+                ..token.clone()
+            })
+        } else {
+            let text = &self.source[cursor..token_end];
+            (!text.trim().is_empty()).then(|| Token {
+                text,
+                shape: token.shape.clone(),
+                gap_before: "",
+                start: cursor,
+            })
+        };
+
+        (self.arena.concat(docs), rest)
+    }
+
+    /// Format a table literal as a whole and skip past its tokens.
+    ///
+    /// The header row and each data row get their own line, with cells
+    /// padded into columns when `align_tables` is set and the padded rows fit
+    /// in `max_width`. A table on one line inside a string interpolation
+    /// stays there, and a table holding comments keeps its lines as written.
+    fn format_table(&mut self, table: usize, gap: &'a str) -> Doc<'a> {
+        let gap_doc = if std::mem::take(&mut self.skip_next_gap) {
+            self.arena.nil()
+        } else {
+            self.format_gap(gap)
+        };
+        let (open, close) = (self.tables[table].open, self.tables[table].close);
+        self.index = self.tokens.partition_point(|t| t.start <= close);
+        if self.tables[table].comments {
+            return gap_doc.append(self.format_commented_table(open, close));
+        }
+
+        let spaced = self.config.bracket_spacing == BracketSpacing::Spaced;
+        let rows = &self.table_cells[table];
+        let rendered = |widths: Option<&[usize]>| -> Vec<String> {
+            rows.iter()
+                .map(|cells| render_row(cells, widths, spaced))
+                .collect()
+        };
+
+        let flat_rows = rendered(None);
+        if self.interp_depth > 0 && !self.source[open..=close].contains('\n') {
+            let (header, body) = flat_rows.split_first().expect("tables have a header row");
+            let flat = format!(
+                "{open}{header}; {body}{close}",
+                open = if spaced { "[ " } else { "[" },
+                body = body.join(", "),
+                close = if spaced { " ]" } else { "]" },
+            );
+            return gap_doc.append(self.arena.text(flat));
+        }
+
+        let trailing = if self.config.trailing_comma == TrailingComma::Always {
+            ","
+        } else {
+            ""
+        };
+        let separator = |i: usize| if i == 0 { ";" } else { trailing };
+        self.indent_level += 1;
+        let indent = self.indent_str();
+        let widths = column_widths(rows);
+        let aligned = rendered(Some(&widths));
+        let lines = if self.config.align_tables
+            && aligned.iter().enumerate().all(|(i, row)| {
+                indent.len() + row.width() + separator(i).len() <= self.config.max_width
+            }) {
+            aligned
+        } else {
+            flat_rows
+        };
+        self.indent_level -= 1;
+
+        let mut docs = vec![gap_doc, self.arena.text("[")];
+        for (i, line) in lines.into_iter().enumerate() {
+            docs.push(self.arena.hardline());
+            docs.push(self.arena.text(indent.clone()));
+            docs.push(self.arena.text(line));
+            docs.push(self.arena.text(separator(i)));
+        }
+        docs.push(self.arena.hardline());
+        docs.push(self.arena.text(self.indent_str()));
+        docs.push(self.arena.text("]"));
+        self.arena.concat(docs)
+    }
+
+    /// Lay out a table holding comments line by line as written, with its
+    /// rows indented one level and its closing bracket at the current level.
+    fn format_commented_table(&self, open: usize, close: usize) -> Doc<'a> {
+        let outer = self.indent_str();
+        let inner = format!("{outer}{}", " ".repeat(self.config.indent_width));
+        let mut lines = self.source[open..=close].lines();
+        let first = lines.next().unwrap_or_default().trim_end();
+        let mut docs = vec![self.arena.text(first)];
+        for line in lines {
+            let line = line.trim();
+            docs.push(self.arena.hardline());
+            if !line.is_empty() {
+                let indent = if line.starts_with(']') {
+                    &outer
+                } else {
+                    &inner
+                };
+                docs.push(self.arena.text(format!("{indent}{line}")));
+            }
+        }
+        self.arena.concat(docs)
+    }
+
+    /// Format a match arm's head (`pattern if guard =>`) and continue with its body.
+    ///
+    /// The first arm also opens the match block and decides its layout: arms
+    /// stay on one line, separated by commas, only if the source had them on
+    /// one line and the block fits in `max_width`. Otherwise each arm gets its
+    /// own line, keeping comments and a blank line between arms.
+    fn format_match_arm(&mut self, m: usize, arm: usize, gap: &'a str) -> Doc<'a> {
+        let mut docs = Vec::new();
+
+        if arm == 0 {
+            let block = &self.matches[m];
+            let text = &self.source[block.open..=block.close];
+            let (width, multiline) = flat_width(text);
+            // An arm body that will be expanded can't sit on the match's line
+            let expands = self.config.block_layout != BlockLayout::Preserve
+                && self
+                    .blocks
+                    .values()
+                    .any(|b| b.open > block.open && b.close < block.close && b.must_expand);
+            self.match_broken[m] = multiline
+                || expands
+                || self.line_prefix_width(block.open) + width > self.config.max_width;
+            self.indent_level += 1;
+            self.open_matches.push(m);
+        }
+
+        if self.match_broken[m] {
+            let blank_line = if arm == 0 {
+                let after_open =
+                    &self.source[self.matches[m].open + 1..self.matches[m].arms[0].pattern.start];
+                docs.push(self.arena.text(" {"));
+                let (comments, _) = self.format_gap_comments(after_open, true);
+                docs.push(comments);
+                false
+            } else {
+                let (comments, blank_line) = self.format_gap_comments(gap, false);
+                docs.push(comments);
+                blank_line
+            };
+
+            // Patterns are padded to a common width in display columns
+            let head = &self.match_heads[m][arm];
+            let width = if self.config.align_match_arrows {
+                self.match_heads[m]
+                    .iter()
+                    .map(|head| head.width())
+                    .max()
+                    .unwrap_or(0)
+            } else {
+                0
+            };
+            let pad = " ".repeat(width.saturating_sub(head.width()));
+            if blank_line {
+                docs.push(self.arena.hardline());
+            }
+            docs.push(self.arena.hardline());
+            docs.push(self.arena.text(self.indent_str()));
+            docs.push(self.arena.text(format!("{head}{pad} => ")));
+        } else {
+            docs.push(self.arena.text(if arm == 0 { " { " } else { ", " }));
+            docs.push(self.arena.text(format!("{} => ", self.match_heads[m][arm])));
+        }
+
+        self.index = self.arm_bodies[m][arm];
+        self.skip_next_gap = true;
+
+        self.arena.concat(docs)
+    }
 
-        let gap_doc = self.format_gap(token.gap_before);
-        let token_doc = self.format_token(token);
+    /// Format the comments in a gap between match arms or collection entries.
+    ///
+    /// A comment on the same line as the previous item stays there. Other
+    /// comments go on their own lines at the current indentation, and blank
+    /// lines before them collapse to one. Separators like `,` are dropped.
+    /// Also returns whether a blank line follows the last comment.
+    fn format_gap_comments(&self, gap: &'a str, after_open: bool) -> (Doc<'a>, bool) {
+        let mut docs = Vec::new();
+        let mut blank = false;
+        let lines: Vec<&str> = gap.split('\n').collect();
+
+        for (i, line) in lines.iter().enumerate() {
+            let comment = line.find('#').map(|pos| line[pos..].trim_end());
+            match comment {
+                Some(comment) if i == 0 && !after_open => {
+                    docs.push(self.arena.space());
+                    docs.push(self.arena.text(comment));
+                }
+                Some(comment) => {
+                    if std::mem::take(&mut blank) && !after_open {
+                        docs.push(self.arena.hardline());
+                    }
+                    docs.push(self.arena.hardline());
+                    docs.push(self.arena.text(self.indent_str()));
+                    docs.push(self.arena.text(comment));
+                }
+                None if i > 0 && i + 1 < lines.len() => {
+                    blank |= line.trim().trim_matches(',').trim().is_empty();
+                }
+                None => {}
+            }
+        }
 
-        gap_doc.append(token_doc)
+        (self.arena.concat(docs), blank)
     }
 
     /// Format a gap (whitespace and comments between tokens).
@@ -220,7 +1114,7 @@ impl<'a> Formatter<'a> {
     ///
     /// 1. **Empty gaps**: Return nil
     /// 2. **Single-line whitespace**: Collapse to a single space
-    /// 3. **Single-line with content**: Wrap content (like `=`) with spaces
+    /// 3. **Single-line with content**: Delegate to `format_inline_gap`
     /// 4. **Structural gaps**: Delegate to `format_structural_gap` for braces/commas
     /// 5. **Multiline with comments**: Preserve comment positions and blank lines
     /// 6. **Multiline with other content**: Handle operators spanning lines
@@ -233,21 +1127,10 @@ impl<'a> Formatter<'a> {
         }
 
         let has_newline = gap.contains('\n');
-        let gap_trimmed = gap.trim();
 
         // Handle simple cases first
         if !has_newline {
-            // No newlines - check if there's content other than whitespace
-            if gap_trimmed.is_empty() {
-                // Pure whitespace - becomes a single space
-                return self.arena.space();
-            }
-            // Content like "=" - wrap with spaces
-            return self
-                .arena
-                .space()
-                .append(self.arena.text(gap_trimmed))
-                .append(self.arena.space());
+            return self.format_inline_gap(gap);
         }
 
         // Check if gap contains structural delimiters (e.g., match expression braces)
@@ -354,8 +1237,12 @@ impl<'a> Formatter<'a> {
             first_line = false;
         }
 
-        // If gap had newlines but we haven't emitted anything, emit a single newline
-        if docs.is_empty() && has_newline {
+        // If gap had newlines but we haven't emitted anything, emit a single
+        // newline. The same goes for content hugging the previous token, like
+        // the `?` of `$x.name?` at the end of a line.
+        let hugging = !gap.starts_with(char::is_whitespace)
+            && gap.lines().skip(1).all(|line| line.trim().is_empty());
+        if has_newline && (docs.is_empty() || hugging && !emitted_newline) {
             docs.push(self.arena.hardline());
             docs.push(self.arena.text(self.indent_str()));
         }
@@ -363,6 +1250,33 @@ impl<'a> Formatter<'a> {
         self.arena.concat(docs)
     }
 
+    /// Format a gap without newlines.
+    ///
+    /// Whitespace becomes a single space, `;` hugs the statement before it,
+    /// content hugging the previous token (like the `.` of `$x.name` or the
+    /// `?` of `$x.name?`) stays that way, and other content like `=` is
+    /// wrapped with spaces.
+    fn format_inline_gap(&self, gap: &'a str) -> Doc<'a> {
+        let gap_trimmed = gap.trim();
+        if gap_trimmed.is_empty() {
+            self.arena.space()
+        } else if gap_trimmed.starts_with(';') {
+            self.arena.text(gap_trimmed).append(self.arena.space())
+        } else if !gap.starts_with(char::is_whitespace) {
+            let text = self.arena.text(gap_trimmed);
+            if gap.ends_with(char::is_whitespace) {
+                text.append(self.arena.space())
+            } else {
+                text
+            }
+        } else {
+            self.arena
+                .space()
+                .append(self.arena.text(gap_trimmed))
+                .append(self.arena.space())
+        }
+    }
+
     /// Format a gap that contains structural delimiters like `{`, `}`, or comma+newline.
     ///
     /// This handles match expressions where braces aren't separate tokens, e.g.:
@@ -495,6 +1409,12 @@ impl<'a> Formatter<'a> {
             return self.arena.nil();
         }
 
+        if let Some(key) = self.keys.get(&token.start)
+            && self.interp_depth == 0
+        {
+            return self.arena.text(key.clone());
+        }
+
         match token.shape {
             FlatShape::StringInterpolation => {
                 if token.text.starts_with('$') {
@@ -508,6 +1428,7 @@ impl<'a> Formatter<'a> {
             FlatShape::Pipe => self.format_pipe_token(token),
             FlatShape::Record | FlatShape::List => self.format_collection_token(token),
             FlatShape::String => self.format_string_token(token),
+            FlatShape::Signature => self.format_signature_token(token),
             _ => self.arena.text(token.text),
         }
     }
@@ -540,10 +1461,13 @@ impl<'a> Formatter<'a> {
             return self.format_multi_close(token.text);
         }
 
+        // Offset of the `{`, for measuring closure parameters
+        let open_offset = token.start + (token.text.len() - token.text.trim_start().len());
+
         if has_open && has_close {
-            self.format_complete_block(trimmed, has_newline)
+            self.format_complete_block(trimmed, open_offset, has_newline)
         } else if has_open {
-            self.format_block_open(trimmed, has_newline)
+            self.format_block_open(trimmed, open_offset, has_newline)
         } else if has_close {
             self.format_block_close(has_newline)
         } else {
@@ -580,6 +1504,7 @@ impl<'a> Formatter<'a> {
             let brace_count = trimmed.chars().filter(|&c| c == '}').count();
             for j in 0..brace_count {
                 self.indent_level = self.indent_level.saturating_sub(1);
+                self.block_stack.pop();
                 if has_newline && (j > 0 || !is_first) {
                     let indent = self.indent_str();
                     docs.push(self.arena.hardline());
@@ -593,13 +1518,22 @@ impl<'a> Formatter<'a> {
     }
 
     /// Format a complete block `{ ... }` that's in a single token.
-    fn format_complete_block(&mut self, trimmed: &'a str, source_multiline: bool) -> Doc<'a> {
+    fn format_complete_block(
+        &mut self,
+        trimmed: &'a str,
+        offset: usize,
+        source_multiline: bool,
+    ) -> Doc<'a> {
         let inner = trimmed
             .strip_prefix('{')
             .and_then(|s| s.strip_suffix('}'))
             .unwrap_or("");
 
         let (params, body) = parse_closure_params(inner);
+        let (params, params_broken) = params.map_or((None, false), |p| {
+            let (doc, broken) = self.format_closure_params(&p, offset);
+            (Some(doc), broken)
+        });
         let body_trimmed = body.trim();
 
         if body_trimmed.is_empty() {
@@ -607,7 +1541,7 @@ impl<'a> Formatter<'a> {
                 return self
                     .arena
                     .text("{")
-                    .append(self.arena.text(p))
+                    .append(p)
                     .append(self.arena.space())
                     .append(self.arena.text("}"));
             }
@@ -618,13 +1552,13 @@ impl<'a> Formatter<'a> {
                 .append(self.arena.text("}"));
         }
 
-        let force_multiline = source_multiline || body.contains('\n');
+        let force_multiline = source_multiline || params_broken || body.contains('\n');
 
         let open = self.arena.text("{");
         let close = self.arena.text("}");
 
         let open_with_params = if let Some(p) = params {
-            open.append(self.arena.text(p))
+            open.append(p)
         } else {
             open
         };
@@ -664,25 +1598,40 @@ impl<'a> Formatter<'a> {
     }
 
     /// Format an opening brace `{` or `{|params|`.
-    fn format_block_open(&mut self, trimmed: &'a str, source_multiline: bool) -> Doc<'a> {
+    fn format_block_open(
+        &mut self,
+        trimmed: &'a str,
+        offset: usize,
+        source_multiline: bool,
+    ) -> Doc<'a> {
         let (estimated_len, inner_has_newline) = self.estimate_block_length();
-        let force_multiline =
-            source_multiline || inner_has_newline || estimated_len > self.config.max_width;
 
         let after_brace = trimmed.strip_prefix('{').unwrap_or(trimmed);
         let (params, rest) = parse_closure_params(after_brace);
+        let has_params = params.is_some();
 
         let open = self.arena.text("{");
+        let mut params_broken = false;
         let open_with_params = if let Some(p) = params {
-            open.append(self.arena.text(p))
+            let (doc, broken) = self.format_closure_params(&p, offset);
+            params_broken = broken;
+            open.append(doc)
         } else {
             open
         };
 
+        let force_multiline = params_broken
+            || self.is_broken_branch(offset)
+            || self.expands_block(offset).unwrap_or_else(|| {
+                source_multiline
+                    || inner_has_newline
+                    || self.block_prefix_width(offset) + estimated_len > self.config.max_width
+            });
+        self.block_stack.push(force_multiline);
         self.indent_level += 1;
 
         // Check if there's content after the opening brace (e.g., comments)
-        let rest_trimmed = if params.is_some() {
+        let rest_trimmed = if has_params {
             rest.trim()
         } else {
             after_brace.trim()
@@ -719,8 +1668,10 @@ impl<'a> Formatter<'a> {
     /// Format a closing brace `}`.
     fn format_block_close(&mut self, has_newline: bool) -> Doc<'a> {
         self.indent_level = self.indent_level.saturating_sub(1);
+        let opened_multiline = self.block_stack.pop().unwrap_or(false);
+        let preserve = self.config.block_layout == BlockLayout::Preserve;
 
-        if has_newline {
+        if opened_multiline || preserve && has_newline {
             let indent = self.indent_str();
             self.arena
                 .hardline()
@@ -737,7 +1688,7 @@ impl<'a> Formatter<'a> {
             |shape| matches!(shape, FlatShape::Block | FlatShape::Closure),
             starts_with_open_brace,
             ends_with_close_brace,
-            |trimmed, _depth| trimmed.len(),
+            |trimmed, _depth| trimmed.width(),
         )
     }
 
@@ -799,52 +1750,482 @@ impl<'a> Formatter<'a> {
         doc
     }
 
-    /// Format a collection token (record or list).
-    fn format_collection_token(&mut self, token: &Token<'a>) -> Doc<'a> {
-        let trimmed = token.text.trim();
-        let has_newline = token.text.contains('\n');
+    /// Format a `def` signature: its parameter list and input/output types.
+    ///
+    /// Parameters are normalized to `name: type = default` and joined on one
+    /// line. They go one per line instead if the signature doesn't fit in
+    /// `max_width`, has comments, or was already multiline in the source.
+    /// A list of input/output types breaks one entry per line the same way,
+    /// and is tried first so short parameter lists can stay on one line.
+    /// Signatures that can't be parsed are left as written.
+    fn format_signature_token(&mut self, token: &Token<'a>) -> Doc<'a> {
+        let Some(signature) = parse_signature(token.text) else {
+            return self.arena.text(token.text);
+        };
 
-        match trimmed {
-            "{" | "[" => self.format_collection_open(trimmed, has_newline),
-            "}" | "]" => self.format_collection_close(trimmed, has_newline),
-            ":" => self.arena.text(":").append(self.arena.space()),
-            "," => {
-                // Check if we're in multiline mode
-                let is_multiline = self.is_in_multiline_collection();
-                if is_multiline {
-                    let indent = self.indent_str();
-                    self.arena
-                        .text(",")
-                        .append(self.arena.hardline())
-                        .append(self.arena.text(indent))
-                } else {
-                    self.arena.text(",").append(self.arena.space())
-                }
-            }
+        let params: Vec<String> = signature.list.params.iter().map(Param::render).collect();
+        let params_flat = format!("[{}]", params.join(", "));
+        let io_flat = signature
+            .io
+            .as_ref()
+            .map_or_else(|| signature.rest.trim_end().to_string(), IoTypes::render);
+        let io_list = signature.io.as_ref().filter(|io| io.list);
+
+        // Account for the ` {` of the body that follows
+        let prefix = self.line_prefix_width(token.start);
+        let fits = |text_width: usize| text_width + " {".len() <= self.config.max_width;
+
+        let break_params = signature.list.multiline || signature.list.has_comments();
+        let break_io = io_list.is_some_and(|io| io.multiline);
+
+        let end = token.start + token.text.len();
+        if !break_params && !break_io && fits(prefix + params_flat.width() + io_flat.width()) {
+            self.signature_end = Some((end, prefix + params_flat.width() + io_flat.width()));
+            return self.arena.text(params_flat + &io_flat);
+        }
+
+        let (params_doc, io_column) =
+            if break_params || io_list.is_none() || !fits(prefix + params_flat.len() + ": [".len())
+            {
+                let column = self.indent_level * self.config.indent_width + "]".len();
+                (
+                    self.format_broken_params(&signature.list, params, "[", "]"),
+                    column,
+                )
+            } else {
+                let column = prefix + params_flat.width();
+                (self.arena.text(params_flat), column)
+            };
+
+        let (io_doc, end_column) = match io_list {
+            Some(io) if io.multiline || !fits(io_column + io_flat.width()) => (
+                self.format_broken_io_types(io),
+                self.indent_level * self.config.indent_width + "]".len(),
+            ),
             _ => {
-                if ends_with_close_bracket(trimmed) {
-                    self.format_collection_close_complex(trimmed, has_newline)
-                } else if has_newline && trimmed.is_empty() {
-                    self.format_newline_separator()
-                } else if has_newline {
-                    self.format_token_with_newline(token)
-                } else {
-                    self.arena.text(trimmed)
-                }
+                let column = io_column + io_flat.width();
+                (self.arena.text(io_flat), column)
             }
-        }
+        };
+        self.signature_end = Some((end, end_column));
+        params_doc.append(io_doc)
     }
 
-    /// Check if we're inside a multiline collection using the tracked stack.
-    fn is_in_multiline_collection(&self) -> bool {
-        self.multiline_stack.last().copied().unwrap_or(false)
-    }
+    /// Format parameters one per line between `open` and `close` delimiters.
+    fn format_broken_params(
+        &self,
+        list: &ParamList<'a>,
+        params: Vec<String>,
+        open: &'static str,
+        close: &'static str,
+    ) -> Doc<'a> {
+        let outer_indent = self.indent_str();
+        let indent = " ".repeat((self.indent_level + 1) * self.config.indent_width);
+        let separator = if self.config.trailing_comma == TrailingComma::Always {
+            ","
+        } else {
+            ""
+        };
+
+        let mut docs: Vec<Doc<'a>> = vec![self.arena.text(open)];
+        for (param, rendered) in list.params.iter().zip(params) {
+            for comment in &param.comments {
+                docs.push(self.arena.hardline());
+                docs.push(self.arena.text(indent.clone()));
+                docs.push(self.arena.text(*comment));
+            }
+            docs.push(self.arena.hardline());
+            docs.push(self.arena.text(indent.clone()));
+            docs.push(self.arena.text(rendered));
+            docs.push(self.arena.text(separator));
+            if let Some(comment) = param.comment {
+                docs.push(self.arena.space());
+                docs.push(self.arena.text(comment));
+            }
+        }
+        for comment in &list.trailing_comments {
+            docs.push(self.arena.hardline());
+            docs.push(self.arena.text(indent.clone()));
+            docs.push(self.arena.text(*comment));
+        }
+        docs.push(self.arena.hardline());
+        docs.push(self.arena.text(outer_indent));
+        docs.push(self.arena.text(close));
+
+        self.arena.concat(docs)
+    }
+
+    /// Format a list of input/output types one entry per line.
+    fn format_broken_io_types(&self, io: &IoTypes) -> Doc<'a> {
+        let indent = " ".repeat((self.indent_level + 1) * self.config.indent_width);
+        let separator = if self.config.trailing_comma == TrailingComma::Always {
+            ","
+        } else {
+            ""
+        };
+
+        let mut docs: Vec<Doc<'a>> = vec![self.arena.text(": [")];
+        for entry in &io.entries {
+            docs.push(self.arena.hardline());
+            docs.push(self.arena.text(indent.clone()));
+            docs.push(self.arena.text(entry.clone()));
+            docs.push(self.arena.text(separator));
+        }
+        docs.push(self.arena.hardline());
+        docs.push(self.arena.text(self.indent_str()));
+        docs.push(self.arena.text("]"));
+
+        self.arena.concat(docs)
+    }
+
+    /// Format closure parameters as `|x: int, y|`.
+    ///
+    /// Parameters are normalized like `def` parameters and go one per line if
+    /// they don't fit after the `{` at `offset`. Lists that can't be parsed
+    /// are left as written. Also returns whether the parameters were broken,
+    /// since the body then has to start on its own line.
+    fn format_closure_params(&self, params: &ClosureParams<'a>, offset: usize) -> (Doc<'a>, bool) {
+        let Some(list) = &params.list else {
+            return (self.arena.text(params.text), false);
+        };
+
+        let rendered: Vec<String> = list.params.iter().map(Param::render).collect();
+        let flat = format!("|{}|", rendered.join(", "));
+        if self.block_prefix_width(offset) + "{".len() + flat.width() <= self.config.max_width {
+            return (self.arena.text(flat), false);
+        }
+
+        (self.format_broken_params(list, rendered, "|", "|"), true)
+    }
+
+    /// Estimate the output width of the line up to the token at `offset`.
+    ///
+    /// Measured from the output column of the tokens already emitted on the
+    /// line where possible. Otherwise uses the source text before the token
+    /// on its output line, with whitespace collapsed, plus the current
+    /// indentation and one separating space.
+    fn line_prefix_width(&self, offset: usize) -> usize {
+        if let Some(column) = self.output_column(offset) {
+            return column;
+        }
+        let (line_start, pipe_prefix) = self.output_line_start(offset);
+        let (prefix, _) = flat_width(&self.source[line_start..offset]);
+        self.indent_level * self.config.indent_width
+            + pipe_prefix
+            + prefix
+            + usize::from(prefix > 0)
+    }
+
+    /// Find where the output line holding `offset` starts in the source.
+    ///
+    /// That is the start of its source line, unless a broken pipeline or wrap
+    /// puts the stage or piece holding `offset` on a line of its own. Also
+    /// returns the width of the `| ` that leads such a stage.
+    fn output_line_start(&self, offset: usize) -> (usize, usize) {
+        let line_start = self.source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_start = line_start.max(self.piece_start(offset));
+        let stage = self.open_broken_pipelines.last().and_then(|&idx| {
+            self.pipelines[idx]
+                .stages
+                .iter()
+                .skip(1)
+                .find(|s| s.contains(&offset))
+        });
+        match stage {
+            Some(stage) if stage.start > line_start => {
+                let leading = self.config.pipe_position == PipePosition::Leading;
+                (stage.start, if leading { "| ".len() } else { 0 })
+            }
+            _ => (line_start, 0),
+        }
+    }
+
+    /// Offset of the piece holding `offset` in the innermost broken wrap,
+    /// which starts a line of its own, or 0 outside of one.
+    fn piece_start(&self, offset: usize) -> usize {
+        self.open_wraps.last().map_or(0, |start| {
+            let wrap = &self.wraps[start];
+            std::iter::once(wrap.first_piece())
+                .chain(wrap.breaks.iter().copied())
+                .filter(|&o| o <= offset)
+                .max()
+                .unwrap_or(0)
+        })
+    }
+
+    /// Check whether the block opening at `offset` is a branch of a broken chain.
+    ///
+    /// The decision is made once per chain, at its first branch, so that every
+    /// branch is laid out the same way. Chains stay on one line only if each
+    /// branch is a single statement on one line and the whole chain fits in
+    /// `max_width`.
+    fn is_broken_branch(&mut self, offset: usize) -> bool {
+        let Some(&idx) = self.branch_owners.get(&offset) else {
+            return false;
+        };
+        if let Some(broken) = self.chain_broken[idx] {
+            return broken;
+        }
+
+        let chain = &self.chains[idx];
+        let multiline = if self.config.block_layout == BlockLayout::Preserve {
+            chain.multiline
+        } else {
+            chain
+                .branches
+                .iter()
+                .any(|branch| self.blocks.get(&branch.start).is_none_or(|b| b.must_expand))
+        };
+        let broken = multiline || {
+            let width = collapsed_width(&self.source[chain.start..chain.end]);
+            self.line_prefix_width(chain.start) + width > self.config.max_width
+        };
+        self.chain_broken[idx] = Some(broken);
+        broken
+    }
+
+    /// Decide whether the block opening at `offset` puts its body on its own lines.
+    ///
+    /// Returns `None` under `block_layout = "preserve"`, or for blocks missing
+    /// from the AST, leaving the decision to the source layout.
+    fn expands_block(&self, offset: usize) -> Option<bool> {
+        if self.config.block_layout == BlockLayout::Preserve {
+            return None;
+        }
+        let block = self.blocks.get(&offset)?;
+        Some(
+            block.must_expand
+                || self.block_prefix_width(offset) + block.flat_width > self.config.max_width,
+        )
+    }
+
+    /// Estimate the output width of the line up to the block opening at `offset`.
+    ///
+    /// Later branches of a broken chain follow the previous branch's `}`, and
+    /// a `def` body follows its signature, rather than whatever preceded them
+    /// in the source.
+    fn block_prefix_width(&self, offset: usize) -> usize {
+        if let Some((end, column)) = self.signature_end
+            && end <= offset
+            && self.source[end..offset].trim().is_empty()
+        {
+            return column + 1;
+        }
+        if let Some(&idx) = self.branch_owners.get(&offset)
+            && self.chain_broken[idx] == Some(true)
+        {
+            let branches = &self.chains[idx].branches;
+            if let Some(pos) = branches.iter().position(|b| b.start == offset)
+                && pos > 0
+            {
+                let (keyword, _) = flat_width(&self.source[branches[pos - 1].end..offset]);
+                return self.indent_level * self.config.indent_width + "} ".len() + keyword + 1;
+            }
+        }
+        self.line_prefix_width(offset)
+    }
+
+    /// Check whether a pipe belongs to a pipeline that is broken across lines.
+    ///
+    /// The decision is made once per pipeline, at its first pipe. A pipeline is
+    /// broken if the source already had a line break between stages, or if it
+    /// wouldn't fit in `max_width` on one line. Breaking adds one level of
+    /// continuation indent until the pipeline ends.
+    fn is_broken_pipe(&mut self, token: &Token<'a>) -> bool {
+        let Some(&idx) = self.pipe_owners.get(&token.start) else {
+            return false;
+        };
+        if let Some(broken) = self.pipeline_broken[idx] {
+            return broken;
+        }
+
+        // A block kept on one line can't hold a broken pipeline
+        let broken = self.block_stack.last() != Some(&false)
+            && self.should_break_pipeline(&self.pipelines[idx]);
+        self.pipeline_broken[idx] = Some(broken);
+        if broken {
+            self.indent_level += 1;
+            self.open_broken_pipelines.push(idx);
+        }
+        broken
+    }
+
+    /// Decide whether a pipeline needs one stage per line.
+    fn should_break_pipeline(&self, pipeline: &Pipeline) -> bool {
+        let source_broken = pipeline
+            .stages
+            .windows(2)
+            .any(|pair| self.source[pair[0].end..pair[1].start].contains('\n'));
+        if source_broken {
+            return true;
+        }
+
+        // Measure the line holding the first pipe as if the pipeline were flat,
+        // from the output column its first stage starts at, up to the first
+        // stage that spans multiple lines (its remaining lines don't affect
+        // this one)
+        let first_pipe = pipeline.pipes.first().copied().unwrap_or(0);
+        let stage_start = pipeline.stages[0].start;
+        let line_start = self.source[..first_pipe]
+            .rfind('\n')
+            .map_or(0, |i| i + 1)
+            .max(stage_start);
+        let (prefix, _) = flat_width(&self.source[line_start..first_pipe]);
+        let column = if line_start == stage_start {
+            self.line_prefix_width(stage_start)
+        } else {
+            self.indent_level * self.config.indent_width
+        };
+
+        let mut width = column + prefix;
+        for stage in &pipeline.stages[1..] {
+            let (stage_width, multiline) = flat_width(&self.source[stage.clone()]);
+            width += " | ".len() + stage_width;
+            if multiline {
+                break;
+            }
+        }
+
+        width > self.config.max_width
+    }
+
+    /// Restore indentation for broken pipelines that end at or before `offset`.
+    fn close_broken_pipelines(&mut self, offset: usize) {
+        while self
+            .open_broken_pipelines
+            .last()
+            .is_some_and(|&idx| self.pipelines[idx].end() <= offset)
+        {
+            self.open_broken_pipelines.pop();
+            self.indent_level = self.indent_level.saturating_sub(1);
+        }
+    }
+
+    /// Format a pipe in a pipeline broken across lines, one stage per line.
+    ///
+    /// Takes over the gaps on both sides of the pipe, so the next stage starts
+    /// on a continuation line. Comments in those gaps are kept: a comment that
+    /// trailed the previous stage stays on its line, others get their own lines.
+    fn format_broken_pipe(&mut self, token: &Token<'a>) -> Doc<'a> {
+        let next_gap = self.tokens.get(self.index).map_or("", |t| t.gap_before);
+        self.skip_next_gap = true;
+
+        let comments: Vec<&'a str> = [token.gap_before, next_gap]
+            .into_iter()
+            .flat_map(str::lines)
+            .filter_map(|line| line.find('#').map(|i| line[i..].trim_end()))
+            .collect();
+        // A comment on the same line as the previous stage, on either side of
+        // the pipe, stays at the end of that line.
+        let first_line = if token.gap_before.contains('\n') {
+            token.gap_before.split('\n').next()
+        } else {
+            next_gap.split('\n').next()
+        };
+        let has_inline_comment = first_line.is_some_and(|line| line.contains('#'));
+
+        let indent = self.indent_str();
+        let mut docs: Vec<Doc<'a>> = Vec::new();
+
+        if self.config.pipe_position == PipePosition::Trailing {
+            docs.push(self.arena.text(" |"));
+        }
+        for (i, comment) in comments.iter().enumerate() {
+            if i == 0 && has_inline_comment {
+                docs.push(self.arena.space());
+            } else {
+                docs.push(self.arena.hardline());
+                docs.push(self.arena.text(indent.clone()));
+            }
+            docs.push(self.arena.text(*comment));
+        }
+        docs.push(self.arena.hardline());
+        docs.push(self.arena.text(indent));
+        if self.config.pipe_position == PipePosition::Leading {
+            docs.push(self.arena.text("| "));
+        }
+
+        self.arena.concat(docs)
+    }
+
+    /// Format a collection token (record or list).
+    fn format_collection_token(&mut self, token: &Token<'a>) -> Doc<'a> {
+        let trimmed = token.text.trim();
+        let has_newline = token.text.contains('\n');
+
+        if self.fill.is_some() && (trimmed == "," || trimmed.is_empty()) {
+            return self.format_fill_separator();
+        }
+        if is_empty_collection(trimmed) {
+            return self
+                .arena
+                .text(&trimmed[..1])
+                .append(&trimmed[trimmed.len() - 1..]);
+        }
+
+        match trimmed {
+            "{" | "[" => self.format_collection_open(trimmed, token.start, has_newline),
+            "}" | "]" => self.format_collection_close(trimmed, has_newline),
+            ":" => {
+                let padding =
+                    if self.config.align_record_values && self.is_in_multiline_collection() {
+                        self.key_padding.get(&token.start).copied().unwrap_or(0)
+                    } else {
+                        0
+                    };
+                self.arena.text(":").append(" ".repeat(padding + 1))
+            }
+            "," => {
+                if self.is_in_multiline_collection() {
+                    self.format_entry_separator(token.text)
+                } else {
+                    self.arena.text(",").append(self.arena.space())
+                }
+            }
+            _ => {
+                if ends_with_close_bracket(trimmed) {
+                    self.format_collection_close_complex(token.text, has_newline)
+                } else if trimmed.is_empty() && !self.is_in_multiline_collection() {
+                    self.arena.text(",").append(self.arena.space())
+                } else if has_newline || trimmed.is_empty() {
+                    self.format_entry_separator(token.text)
+                } else {
+                    self.arena.text(trimmed)
+                }
+            }
+        }
+    }
+
+    /// Check if we're inside a multiline collection using the tracked stack.
+    fn is_in_multiline_collection(&self) -> bool {
+        self.multiline_stack.last().copied().unwrap_or(false)
+    }
 
     /// Format opening bracket for a collection.
-    fn format_collection_open(&mut self, bracket: &'a str, source_multiline: bool) -> Doc<'a> {
-        let (estimated_len, inner_has_newline) = self.estimate_collection_length();
-        let force_multiline =
-            source_multiline || inner_has_newline || estimated_len > self.config.max_width;
+    ///
+    /// Collections with comments are always multiline. Otherwise the
+    /// `collection_layout` setting decides: `preserve` keeps collections that
+    /// were multiline in the source that way, while `auto` and `fill` join
+    /// any collection that fits in `max_width`. A `fill` list that doesn't
+    /// fit packs its items onto as few lines as it can.
+    fn format_collection_open(
+        &mut self,
+        bracket: &'a str,
+        offset: usize,
+        source_multiline: bool,
+    ) -> Doc<'a> {
+        let contents = self.scan_collection(bracket == "[");
+        let layout = self.config.collection_layout;
+        let force_multiline = contents.comments
+            || if layout == CollectionLayout::Preserve {
+                let (estimated_len, inner_has_newline) = self.estimate_collection_length();
+                source_multiline
+                    || inner_has_newline
+                    || self.line_prefix_width(offset) + estimated_len > self.config.max_width
+            } else {
+                contents.multiline_items
+                    || self.line_prefix_width(offset) + contents.flat_width > self.config.max_width
+            };
 
         // Track multiline state for this collection
         self.multiline_stack.push(force_multiline);
@@ -854,6 +2235,15 @@ impl<'a> Formatter<'a> {
         if force_multiline {
             self.indent_level += 1;
             let indent = self.indent_str();
+            if layout == CollectionLayout::Fill
+                && let Some(widths) = contents.simple_items
+            {
+                self.fill = Some(Fill {
+                    column: indent.len() + widths[0],
+                    widths,
+                    item: 0,
+                });
+            }
             open.append(self.arena.hardline())
                 .append(self.arena.text(indent))
         } else if self.config.bracket_spacing == BracketSpacing::Spaced {
@@ -866,9 +2256,10 @@ impl<'a> Formatter<'a> {
     /// Format closing bracket for a collection.
     fn format_collection_close(&mut self, bracket: &'a str, source_multiline: bool) -> Doc<'a> {
         // Pop multiline state for this collection
-        self.multiline_stack.pop();
+        let multiline = self.multiline_stack.pop().unwrap_or(source_multiline);
+        self.fill = None;
 
-        if source_multiline {
+        if multiline {
             self.indent_level = self.indent_level.saturating_sub(1);
             let indent = self.indent_str();
             let trailing = if self.config.trailing_comma == TrailingComma::Always {
@@ -888,23 +2279,49 @@ impl<'a> Formatter<'a> {
     }
 
     /// Format complex closing like "?}" or content followed by closing bracket.
+    ///
+    /// In a multiline collection, a trailing comma and comments before the
+    /// bracket are separators rather than content: the comma follows the
+    /// `trailing_comma` setting and the comments keep their place.
     fn format_collection_close_complex(
         &mut self,
-        trimmed: &'a str,
+        text: &'a str,
         source_multiline: bool,
     ) -> Doc<'a> {
         // Pop multiline state for this collection
-        self.multiline_stack.pop();
+        let multiline = self.multiline_stack.pop().unwrap_or(source_multiline);
+        self.fill = None;
 
+        let trimmed = text.trim();
         let bracket = if trimmed.contains('}') { '}' } else { ']' };
         let prefix = trimmed.trim_end_matches(bracket);
 
+        let before = &text[..text.rfind(bracket).unwrap_or(text.len())];
+        let only_separators = before.lines().all(|line| {
+            let code = line.find('#').map_or(line, |pos| &line[..pos]);
+            code.trim().trim_matches(',').trim().is_empty()
+        });
+        if multiline && only_separators {
+            let (comments, _) = self.format_gap_comments(before, false);
+            self.indent_level = self.indent_level.saturating_sub(1);
+            let trailing = if self.config.trailing_comma == TrailingComma::Always {
+                self.arena.text(",")
+            } else {
+                self.arena.nil()
+            };
+            return trailing
+                .append(comments)
+                .append(self.arena.hardline())
+                .append(self.arena.text(self.indent_str()))
+                .append(self.arena.text(if bracket == '}' { "}" } else { "]" }));
+        }
+
         let mut doc = self.arena.nil();
-        if !prefix.is_empty() {
+        if !only_separators {
             doc = doc.append(self.arena.text(prefix));
         }
 
-        if source_multiline {
+        if multiline {
             self.indent_level = self.indent_level.saturating_sub(1);
             let indent = self.indent_str();
             let trailing = if self.config.trailing_comma == TrailingComma::Always {
@@ -924,6 +2341,103 @@ impl<'a> Formatter<'a> {
         }
     }
 
+    /// Emit the separator before the next item of a filled list.
+    ///
+    /// The item joins the current line if it fits there, with room for the
+    /// comma after it, and starts a new line otherwise.
+    fn format_fill_separator(&mut self) -> Doc<'a> {
+        let indent = self.indent_str();
+        let max_width = self.config.max_width;
+        let Some(fill) = self.fill.as_mut() else {
+            return self.arena.nil();
+        };
+
+        fill.item += 1;
+        let width = fill.widths.get(fill.item).copied().unwrap_or(0);
+        if fill.column + 2 + width < max_width {
+            fill.column += 2 + width;
+            self.arena.text(", ")
+        } else {
+            fill.column = indent.len() + width;
+            self.arena
+                .text(",")
+                .append(self.arena.hardline())
+                .append(self.arena.text(indent))
+        }
+    }
+
+    /// Scan the collection whose opening bracket was just consumed.
+    ///
+    /// Items are only reported for lists, since records always put one entry
+    /// per line.
+    fn scan_collection(&self, is_list: bool) -> CollectionContents {
+        let spaced = usize::from(self.config.bracket_spacing == BracketSpacing::Spaced);
+        let mut contents = CollectionContents {
+            comments: false,
+            multiline_items: false,
+            flat_width: 1 + spaced,
+            simple_items: None,
+        };
+        // Number of tokens and width of each item
+        let mut items = vec![(0usize, 0usize)];
+        let mut nested = false;
+        let mut depth = 1usize;
+
+        for token in &self.tokens[self.index..] {
+            let gap = token.gap_before;
+            contents.comments |= gap.contains('#');
+            contents.multiline_items |= gap.contains('\n');
+            if !gap.trim().is_empty() {
+                contents.flat_width += gap.trim().width() + 1;
+            } else if !gap.is_empty() {
+                contents.flat_width += 1;
+            }
+
+            let trimmed = token.text.trim();
+            if matches!(token.shape, FlatShape::Record | FlatShape::List)
+                && !is_empty_collection(trimmed)
+            {
+                contents.comments |= trimmed.contains('#');
+                if is_open_bracket(trimmed) {
+                    depth += 1;
+                    nested = true;
+                    contents.flat_width += 1 + spaced;
+                } else if ends_with_close_bracket(trimmed) {
+                    depth -= 1;
+                    contents.flat_width += 1 + spaced;
+                    if depth == 0 {
+                        break;
+                    }
+                } else {
+                    if depth == 1 {
+                        items.push((0, 0));
+                    }
+                    contents.flat_width += 2;
+                }
+                continue;
+            }
+
+            contents.multiline_items |= token.text.contains('\n');
+            let width = trimmed.width();
+            contents.flat_width += width;
+            if depth == 1
+                && let Some(item) = items.last_mut()
+            {
+                item.0 += 1;
+                item.1 += width;
+            }
+        }
+
+        // A separator right before the closing bracket is a trailing comma
+        if items.len() > 1 && items.last() == Some(&(0, 0)) {
+            items.pop();
+        }
+        if is_list && !nested && items.iter().all(|&(count, _)| count == 1) {
+            contents.simple_items = Some(items.into_iter().map(|(_, width)| width).collect());
+        }
+        contents
+    }
+
     /// Estimate collection length by looking ahead.
     fn estimate_collection_length(&self) -> (usize, bool) {
         self.estimate_delimited_length(
@@ -935,10 +2449,10 @@ impl<'a> Formatter<'a> {
                     1
                 } else if is_close_bracket(trimmed) {
                     usize::from(depth > 1)
-                } else if trimmed == ":" || trimmed == "," {
+                } else if trimmed == ":" || trimmed == "," || trimmed.is_empty() {
                     2
                 } else {
-                    trimmed.len()
+                    trimmed.width()
                 }
             },
         )
@@ -969,13 +2483,15 @@ impl<'a> Formatter<'a> {
         while idx < self.tokens.len() && depth > 0 {
             let t = &self.tokens[idx];
 
-            if t.gap_before.contains('\n') {
+            // A nested delimiter holding a line break is laid out on several
+            // lines, so the one around it is too
+            if t.gap_before.contains('\n') || shape_matches(&t.shape) && t.text.contains('\n') {
                 has_newline = true;
             }
 
             let gap_trimmed = t.gap_before.trim();
             if !gap_trimmed.is_empty() {
-                length += gap_trimmed.len() + 1;
+                length += gap_trimmed.width() + 1;
             } else if !t.gap_before.is_empty() {
                 length += 1;
             }
@@ -997,51 +2513,51 @@ impl<'a> Formatter<'a> {
         (length, has_newline)
     }
 
-    /// Format a newline separator in a collection (acts like comma).
-    fn format_newline_separator(&self) -> Doc<'a> {
-        let indent = self.indent_str();
-        if self.config.trailing_comma == TrailingComma::Always {
-            self.arena
-                .text(",")
-                .append(self.arena.hardline())
-                .append(self.arena.text(indent))
+    /// Format the separator between entries of a multiline collection.
+    ///
+    /// Each entry gets its own line. Comments keep their place, on the
+    /// previous entry's line or on their own, and a blank line between
+    /// entries is kept.
+    fn format_entry_separator(&self, text: &'a str) -> Doc<'a> {
+        let comma = text.trim_start().starts_with(',')
+            || self.config.trailing_comma == TrailingComma::Always;
+        let (comments, blank_line) = self.format_gap_comments(text, false);
+
+        let mut doc = if comma {
+            self.arena.text(",")
         } else {
-            self.arena.hardline().append(self.arena.text(indent))
-        }
-    }
-
-    /// Format a token that contains a newline.
-    fn format_token_with_newline(&self, token: &Token<'a>) -> Doc<'a> {
-        if let Some(comment_start) = token.text.find('#') {
-            let comment = token.text[comment_start..].trim_end();
-            let indent = self.indent_str();
-
-            let mut doc = self.arena.nil();
-            if self.config.trailing_comma == TrailingComma::Always {
-                doc = doc.append(self.arena.text(","));
-            }
-            doc = doc
-                .append(self.arena.space())
-                .append(self.arena.text(comment))
-                .append(self.arena.hardline())
-                .append(self.arena.text(indent));
-            return doc;
+            self.arena.nil()
+        };
+        doc = doc.append(comments);
+        if blank_line {
+            doc = doc.append(self.arena.hardline());
         }
-
-        let indent = self.indent_str();
-        self.arena.hardline().append(self.arena.text(indent))
+        doc.append(self.arena.hardline())
+            .append(self.arena.text(self.indent_str()))
     }
 
     /// Format a string token with quote conversion.
+    ///
+    /// Strings inside an interpolation's expressions keep their quotes, since
+    /// the interpolation's own quotes would end at the first matching one.
     fn format_string_token(&self, token: &Token<'a>) -> Doc<'a> {
+        if self.interp_depth > 0 {
+            return self.arena.text(token.text);
+        }
         let converted = convert_string_quotes(token.text, self.config.quote_style);
         self.arena.text(converted)
     }
 }
 
+/// Whether a gap is nothing but spaces on a single line, or empty.
+fn is_inline_space(gap: &str) -> bool {
+    gap.chars().all(|c| c == ' ' || c == '\t')
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{DigitGrouping, KeyQuotes, NumberCase, QuoteStyle, RedirectionStyle, UnitCase};
 
     #[test]
     fn test_simple_command() {
@@ -1229,4 +2745,969 @@ mod tests {
             "Should preserve inline comment: {result}"
         );
     }
+
+    #[test]
+    fn test_long_pipeline_breaks() {
+        let source = "ls | where size > 10kb | sort-by name | first 3 | to json";
+        let config = Config {
+            max_width: 30,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "ls\n  | where size > 10kb\n  | sort-by name\n  | first 3\n  | to json\n"
+        );
+    }
+
+    #[test]
+    fn test_long_pipeline_breaks_trailing() {
+        let source = "ls | where size > 10kb | sort-by name | first 3 | to json";
+        let config = Config {
+            max_width: 30,
+            pipe_position: PipePosition::Trailing,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "ls |\n  where size > 10kb |\n  sort-by name |\n  first 3 |\n  to json\n"
+        );
+    }
+
+    #[test]
+    fn test_short_pipeline_stays_on_one_line() {
+        let source = "ls | where size > 10kb | first 3";
+        let config = Config {
+            max_width: 40,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(result, "ls | where size > 10kb | first 3\n");
+    }
+
+    #[test]
+    fn test_broken_pipeline_switches_pipe_position() {
+        let source = "ls |\nwhere size > 1 | # big ones\nfirst";
+        let config = Config::default();
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(result, "ls\n  | where size > 1 # big ones\n  | first\n");
+    }
+
+    #[test]
+    fn test_signature_spacing() {
+        let source = "def foo [x:int, y=1, --flag(-f), z?:string, ...rest] { $x }";
+        let config = Config::default();
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "def foo [x: int, y = 1, --flag(-f), z?: string, ...rest] { $x }\n"
+        );
+    }
+
+    #[test]
+    fn test_long_signature_one_param_per_line() {
+        let source = "def foo [first: string, second: int = 42, --verbose(-v)] { 1 }";
+        let config = Config {
+            max_width: 40,
+            trailing_comma: TrailingComma::Never,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "def foo [\n  first: string\n  second: int = 42\n  --verbose(-v)\n] { 1 }\n"
+        );
+    }
+
+    #[test]
+    fn test_io_type_spacing() {
+        let source =
+            "def foo [x: record<a:int,b:string>]:   record<a:int>   ->  list<string> { $in }";
+        let config = Config::default();
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "def foo [x: record<a: int, b: string>]: record<a: int> -> list<string> { $in }\n"
+        );
+    }
+
+    #[test]
+    fn test_long_io_type_list_one_entry_per_line() {
+        let source = "def foo [x: int]: [ string ->  int ,nothing -> int, list<string> -> table<a: int>] { $in }";
+        let config = Config {
+            max_width: 60,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "def foo [x: int]: [\n  string -> int,\n  nothing -> int,\n  list<string> -> table<a: int>,\n] { $in }\n"
+        );
+
+        let short = format_source(
+            "def foo [x: int]: [ string ->  int ,nothing -> int] { $in }",
+            &config,
+        )
+        .unwrap();
+        assert_eq!(
+            short,
+            "def foo [x: int]: [string -> int, nothing -> int] { $in }\n"
+        );
+    }
+
+    #[test]
+    fn test_signature_comments_stay_with_params() {
+        let source = "def foo [\nx:int # the x\n# flags\n--verbose(-v)\n] { 1 }";
+        let config = Config::default();
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "def foo [\n  x: int, # the x\n  # flags\n  --verbose(-v),\n] { 1 }\n"
+        );
+    }
+
+    #[test]
+    fn test_closure_params_normalized() {
+        let source = "do { |x:int , y = 2, ...rest| $x } 1";
+        let config = Config::default();
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(result, "do {|x: int, y = 2, ...rest| $x } 1\n");
+    }
+
+    #[test]
+    fn test_long_closure_params_wrap() {
+        let source = "do {|first: string, second: int = 10| $first } a";
+        let config = Config {
+            max_width: 30,
+            trailing_comma: TrailingComma::Never,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "do {|\n  first: string\n  second: int = 10\n|\n  $first\n} a\n"
+        );
+    }
+
+    #[test]
+    fn test_if_else_chain_on_one_line() {
+        let source = "if true { 1 }   else  if false { 2 } else { 3 }";
+        let config = Config::default();
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(result, "if true { 1 } else if false { 2 } else { 3 }\n");
+    }
+
+    #[test]
+    fn test_if_else_chain_breaks_together() {
+        let source = "if true {\n1\n} else { 2 }";
+        let config = Config::default();
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(result, "if true {\n  1\n} else {\n  2\n}\n");
+
+        let long = "if true { 1 } else { 2 }";
+        let config = Config {
+            max_width: 20,
+            ..Default::default()
+        };
+        let result = format_source(long, &config).unwrap();
+        assert_eq!(result, "if true {\n  1\n} else {\n  2\n}\n");
+    }
+
+    #[test]
+    fn test_detached_else_is_joined() {
+        let source = "if true {\n  1\n}\nelse {\n  2\n}";
+        let config = Config::default();
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(result, "if true {\n  1\n} else {\n  2\n}\n");
+    }
+
+    #[test]
+    fn test_try_catch_on_one_line() {
+        let source = "try { 1 }   catch { |err| 2 }";
+        let config = Config::default();
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(result, "try { 1 } catch {|err| 2 }\n");
+    }
+
+    #[test]
+    fn test_try_catch_breaks_together() {
+        let source = "try {\n  1\n}\ncatch {|err| 2 }";
+        let config = Config::default();
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(result, "try {\n  1\n} catch {|err|\n  2\n}\n");
+    }
+
+    #[test]
+    fn test_match_arms_normalized() {
+        let source = "match $x {\n1 |2   =>   \"low\",\n{a: $a} if $a > 1 => $a\n_ => null\n}";
+        let config = Config::default();
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "match $x {\n  1 | 2 => \"low\"\n  { a: $a } if $a > 1 => $a\n  _ => null\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_short_match_stays_on_one_line() {
+        let source = "match 1 {1 => \"one\",  _ => \"many\"}";
+        let config = Config::default();
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(result, "match 1 { 1 => \"one\", _ => \"many\" }\n");
+    }
+
+    #[test]
+    fn test_align_match_arrows() {
+        let source = "match $x {\n1 => \"one\"\n[$a, $b] => $a\n_ => null\n}";
+        let config = Config {
+            align_match_arrows: true,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "match $x {\n  1          => \"one\"\n  [ $a, $b ] => $a\n  _          => null\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_short_table_gets_one_row_per_line() {
+        let source = "[[a b]; [1 2] [3 4]]";
+        let result = format_source(source, &Config::default()).unwrap();
+        assert_eq!(result, "[\n  [ a, b ];\n  [ 1, 2 ],\n  [ 3, 4 ],\n]\n");
+    }
+
+    #[test]
+    fn test_multiline_table_columns_aligned() {
+        let source = "[\n[name, size];\n[foo, 1]\n[barbaz, 22]\n]";
+        let config = Config {
+            bracket_spacing: BracketSpacing::Compact,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "[\n  [name,   size];\n  [foo,    1],\n  [barbaz, 22],\n]\n"
+        );
+
+        let config = Config {
+            align_tables: false,
+            ..config
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "[\n  [name, size];\n  [foo, 1],\n  [barbaz, 22],\n]\n"
+        );
+    }
+
+    #[test]
+    fn test_long_table_breaks_into_rows() {
+        let source = "let t = [[name, size]; [alpha, 1], [beta, 2]]";
+        let config = Config {
+            max_width: 30,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "let t = [\n  [ name,  size ];\n  [ alpha, 1 ],\n  [ beta,  2 ],\n]\n"
+        );
+    }
+
+    #[test]
+    fn test_align_record_values() {
+        let source = "{\na: 1\nbbb: 2\n\nlonger_key: 3\ncc: 4\n}";
+        let config = Config {
+            align_record_values: true,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "{\n  a:   1,\n  bbb: 2,\n\n  longer_key: 3,\n  cc:         4,\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_record_alignment_ignores_very_long_keys() {
+        let source = "{\na: 1\na_key_that_is_far_too_long_to_align: 2\nbb: 3\n}";
+        let config = Config {
+            align_record_values: true,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "{\n  a:  1,\n  a_key_that_is_far_too_long_to_align: 2,\n  bb: 3,\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_collection_comments_keep_their_lines() {
+        let source = "{\n  a: 1, # first\n\n  # second\n  b: 2,\n}";
+        let result = format_source(source, &Config::default()).unwrap();
+        assert_eq!(result, "{\n  a: 1, # first\n\n  # second\n  b: 2,\n}\n");
+    }
+
+    #[test]
+    fn test_space_separated_items_get_commas() {
+        let source = "[1 2 3]\n{a: 1 b: 2}";
+        let result = format_source(source, &Config::default()).unwrap();
+        assert_eq!(result, "[ 1, 2, 3 ]\n{ a: 1, b: 2 }\n");
+    }
+
+    #[test]
+    fn test_long_collection_closes_on_own_line() {
+        let source = "def f [] {\nlet x = [aaaaaaaaaa, bbbbbbbbbb, cccccccccc]\nlet y = 1\n}";
+        let config = Config {
+            max_width: 30,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "def f [] {\n  let x = [\n    aaaaaaaaaa,\n    bbbbbbbbbb,\n    cccccccccc,\n  ]\n  let y = 1\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_collection_layout_auto_joins_short_collections() {
+        let source = "let a = {\n  x: 1\n  y: [\n    1,\n    2,\n  ]\n}\nlet b = [\n  1 # one\n]";
+        let config = Config {
+            collection_layout: CollectionLayout::Auto,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "let a = { x: 1, y: [ 1, 2 ] }\nlet b = [\n  1, # one\n]\n"
+        );
+    }
+
+    #[test]
+    fn test_collection_layout_fill_packs_list_items() {
+        let source = "let x = [alpha beta gamma delta epsilon zeta eta theta]";
+        let config = Config {
+            collection_layout: CollectionLayout::Fill,
+            max_width: 30,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "let x = [\n  alpha, beta, gamma, delta,\n  epsilon, zeta, eta, theta,\n]\n"
+        );
+    }
+
+    #[test]
+    fn test_block_layout_auto_collapses_single_statements() {
+        let source = "def f [] {\n  1\n}\nls | each {|x|\n  $x\n}";
+        let config = Config {
+            block_layout: BlockLayout::Auto,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(result, "def f [] { 1 }\nls | each {|x| $x }\n");
+    }
+
+    #[test]
+    fn test_block_layout_auto_expands_several_statements() {
+        let source = "do { if true { a; b } }";
+        let config = Config {
+            block_layout: BlockLayout::Auto,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(result, "do {\n  if true {\n    a\n    b\n  }\n}\n");
+    }
+
+    #[test]
+    fn test_block_layout_always_expand_defs() {
+        let source = "def f [] { 1 }\nls | each {|x|\n  $x\n}";
+        let config = Config {
+            block_layout: BlockLayout::AlwaysExpandDefs,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(result, "def f [] {\n  1\n}\nls | each {|x| $x }\n");
+    }
+
+    #[test]
+    fn test_operator_spacing() {
+        let source = "mut a = 1\nlet b = ($a   ==  2 and   (not  true))\n$a   +=   1";
+        let config = Config::default();
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "mut a = 1\nlet b = ($a == 2 and (not true))\n$a += 1\n"
+        );
+    }
+
+    #[test]
+    fn test_ranges_and_spreads_stay_unspaced() {
+        let source = "let a = [1]\nlet b = 1..=5\nlet c = [...$a, 2]";
+        let config = Config::default();
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "let a = [ 1 ]\nlet b = 1..=5\nlet c = [ ...$a, 2 ]\n"
+        );
+    }
+
+    #[test]
+    fn test_env_prefix_and_flag_equals_stay_unspaced() {
+        let source = "FOO=bar   BAZ=\"a b\" ^env\nls --all=true\nhttp get --max-time=1sec x";
+        let config = Config::default();
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "FOO=bar BAZ=\"a b\" ^env\nls --all=true\nhttp get --max-time=1sec x\n"
+        );
+    }
+
+    #[test]
+    fn test_flag_value_style() {
+        let source = "http get --max-time=1sec x\nhttp get --max-time 1sec x\nls --all=true";
+        let equals = Config {
+            flag_value_style: FlagValueStyle::Equals,
+            ..Default::default()
+        };
+        let space = Config {
+            flag_value_style: FlagValueStyle::Space,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_source(source, &equals).unwrap(),
+            "http get --max-time=1sec x\nhttp get --max-time=1sec x\nls --all=true\n"
+        );
+        assert_eq!(
+            format_source(source, &space).unwrap(),
+            "http get --max-time 1sec x\nhttp get --max-time 1sec x\nls --all=true\n"
+        );
+    }
+
+    #[test]
+    fn test_cell_paths_stay_unspaced() {
+        let source = "let x = {a: 1}\n$x.a?\nls; $x.a";
+        let config = Config::default();
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(result, "let x = { a: 1 }\n$x.a?\nls; $x.a\n");
+    }
+
+    #[test]
+    fn test_redirection_style() {
+        let source = "^cmd out>   a.txt e> b.txt\n^cmd err+out>> c.txt\n^cmd e+o>| lines";
+        let short = Config {
+            redirection_style: RedirectionStyle::Short,
+            ..Default::default()
+        };
+        let long = Config {
+            redirection_style: RedirectionStyle::Long,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_source(source, &short).unwrap(),
+            "^cmd o> a.txt e> b.txt\n^cmd o+e>> c.txt\n^cmd o+e>| lines\n"
+        );
+        assert_eq!(
+            format_source(source, &long).unwrap(),
+            "^cmd out> a.txt err> b.txt\n^cmd out+err>> c.txt\n^cmd out+err>| lines\n"
+        );
+    }
+
+    #[test]
+    fn test_key_quotes_as_needed() {
+        let source =
+            "let x = {\"a\": 1, 'b': 2, `c`: 3, \"foo bar\": 4, 1x: 5}\n$x.\"a\"?.'foo bar'";
+        let config = Config {
+            key_quotes: KeyQuotes::AsNeeded,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_source(source, &config).unwrap(),
+            "let x = { a: 1, b: 2, c: 3, \"foo bar\": 4, \"1x\": 5 }\n$x.a?.\"foo bar\"\n"
+        );
+    }
+
+    #[test]
+    fn test_key_quotes_consistent() {
+        let source = "let x = {a: 1, \"foo bar\": 2}\nlet y = {\"a\": 1, b: 2}\n$x.\"foo bar\".a";
+        let config = Config {
+            key_quotes: KeyQuotes::Consistent,
+            quote_style: QuoteStyle::Single,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_source(source, &config).unwrap(),
+            "let x = { 'a': 1, 'foo bar': 2 }\nlet y = { a: 1, b: 2 }\n$x.'foo bar'.'a'\n"
+        );
+    }
+
+    #[test]
+    fn test_key_quotes_preserve() {
+        let source = "let x = { \"a\": 1, b: 2, `c`: 3 }\n$x.\"a\"\n";
+        let config = Config::default();
+        assert_eq!(format_source(source, &config).unwrap(), source);
+    }
+
+    #[test]
+    fn test_quote_style_picks_fewest_escapes() {
+        let source = "[\"say \\\"hi\\\"\", 'C:\\dir', `a b`, \"tab\\t\", \"\\(x\\)\"]";
+        let config = Config {
+            quote_style: QuoteStyle::Double,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_source(source, &config).unwrap(),
+            "[ 'say \"hi\"', 'C:\\dir', \"a b\", \"tab\\t\", \"\\(x\\)\" ]\n"
+        );
+
+        let config = Config {
+            quote_style: QuoteStyle::Single,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_source("[\"it's\", \"\\(x\\)\", \"a\\\\b\", `a b`]", &config).unwrap(),
+            "[ \"it's\", '(x)', 'a\\b', 'a b' ]\n"
+        );
+    }
+
+    #[test]
+    fn test_quote_style_converts_safe_interpolations() {
+        let source =
+            "let a = 1\nlet x = $\"hi ($a)!\"\nlet y = $'say \"($a)\"'\nlet z = $\"\\(($a)\\)\"";
+        let config = Config {
+            quote_style: QuoteStyle::Single,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_source(source, &config).unwrap(),
+            "let a = 1\nlet x = $'hi ($a)!'\nlet y = $'say \"($a)\"'\nlet z = $\"\\(($a)\\)\"\n"
+        );
+
+        let config = Config {
+            quote_style: QuoteStyle::Double,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_source("let x = $'sum: (1 + 1)'", &config).unwrap(),
+            "let x = $\"sum: (1 + 1)\"\n"
+        );
+    }
+
+    #[test]
+    fn test_quote_style_leaves_raw_strings_and_unsafe_interpolations() {
+        let source = "let x = r#'it's \"raw\"'#\nlet y = $'(echo \"a\") b'\nopen `my file.txt`\n";
+        let config = Config {
+            quote_style: QuoteStyle::Double,
+            ..Default::default()
+        };
+        assert_eq!(format_source(source, &config).unwrap(), source);
+    }
+
+    #[test]
+    fn test_number_case_lower() {
+        let source = "let x = [0xFF, 0o17, 1.5E-3, 0x[AB cd], 0XFF]";
+        let config = Config {
+            number_case: NumberCase::Lower,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_source(source, &config).unwrap(),
+            "let x = [ 0xff, 0o17, 1.5e-3, 0x[ab cd], 0XFF ]\n"
+        );
+    }
+
+    #[test]
+    fn test_unit_case() {
+        let source = "let x = [10KB, 1.5kib, 100b, 3Mb, 2us, 5min]";
+        let config = Config {
+            unit_case: UnitCase::Canonical,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_source(source, &config).unwrap(),
+            "let x = [ 10kB, 1.5KiB, 100B, 3MB, 2\u{00B5}s, 5min ]\n"
+        );
+
+        let config = Config {
+            unit_case: UnitCase::Lower,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_source(source, &config).unwrap(),
+            "let x = [ 10kb, 1.5kib, 100b, 3mb, 2us, 5min ]\n"
+        );
+    }
+
+    #[test]
+    fn test_digit_grouping_thousands() {
+        let source = "let x = [1234, 12345, -1234567, 10_0000, 0xFFFFFF, 1.23456, 100000kb]";
+        let config = Config {
+            digit_grouping: DigitGrouping::Thousands,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_source(source, &config).unwrap(),
+            "let x = [ 1234, 12_345, -1_234_567, 100_000, 0xFFFFFF, 1.23456, 100_000kb ]\n"
+        );
+    }
+
+    #[test]
+    fn test_literals_preserved_by_default() {
+        let source = "let x = [ 0xFF, 1E5, 10KB, 1234567 ]\n";
+        let config = Config::default();
+        assert_eq!(format_source(source, &config).unwrap(), source);
+    }
+
+    #[test]
+    fn test_redirecting_pipe_is_never_broken() {
+        let source = "^cargo build --release --all-features e>| lines | first 10";
+        let config = Config {
+            max_width: 40,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "^cargo build --release --all-features e>| lines | first 10\n"
+        );
+    }
+
+    #[test]
+    fn test_long_condition_breaks_before_logical_operators() {
+        let source = "if $a > 100 and $b < 200 or $a == 300 and $b == 400 { print yes }";
+        let config = Config {
+            max_width: 40,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "if (\n  $a > 100 and $b < 200\n  or $a == 300 and $b == 400\n) {\n  print yes\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_long_where_condition_keeps_its_parentheses() {
+        let source =
+            "ls | where ($it.size > 1000 and $it.name =~ \"foo\" and $it.type == \"file\")";
+        let config = Config {
+            max_width: 60,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "ls\n  | where (\n    $it.size > 1000\n    and $it.name =~ \"foo\"\n    and $it.type == \"file\"\n  )\n"
+        );
+    }
+
+    #[test]
+    fn test_conditions_that_fit_or_use_bare_columns_stay_inline() {
+        let config = Config {
+            max_width: 40,
+            ..Default::default()
+        };
+        let source = "if $a and $b { print yes }\n";
+        assert_eq!(format_source(source, &config).unwrap(), source);
+
+        // Parentheses would turn `size` and `name` into commands
+        let source = "ls | where size > 1000 and name =~ \"foo\" and type == \"file\"\n";
+        let result = format_source(source, &config).unwrap();
+        assert!(result.contains("| where size > 1000 and name =~ \"foo\" and type == \"file\"\n"));
+    }
+
+    #[test]
+    fn test_long_call_wraps_one_argument_per_line() {
+        let source = "let r = http post --content-type application/json $url $body";
+        let config = Config {
+            max_width: 40,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "let r = (http post\n  --content-type application/json\n  $url\n  $body\n)\n"
+        );
+    }
+
+    #[test]
+    fn test_long_call_reuses_its_parentheses() {
+        let source = "(http get --max-time 10sec https://example.com/a)";
+        let config = Config {
+            max_width: 30,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "(http get\n  --max-time 10sec\n  https://example.com/a\n)\n"
+        );
+    }
+
+    #[test]
+    fn test_multiline_list_argument_hugs_the_call() {
+        let source = "http post -t json https://a.b [\n  aaaaaaaaaaaa\n  bbbbbbbbbbbb\n]";
+        let config = Config {
+            max_width: 40,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "http post -t json https://a.b [\n  aaaaaaaaaaaa,\n  bbbbbbbbbbbb,\n]\n"
+        );
+    }
+
+    #[test]
+    fn test_closure_in_broken_pipeline_expands_as_a_unit() {
+        let source =
+            "sys disks | where mount == \"/\" | each {|disk| $disk | select device free total }";
+        let config = Config {
+            max_width: 40,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "sys disks\n  | where mount == \"/\"\n  | each {|disk|\n    $disk | select device free total\n  }\n"
+        );
+        assert_eq!(format_source(&result, &config).unwrap(), result);
+    }
+
+    #[test]
+    fn test_multiline_closure_stays_on_its_stage_line() {
+        let source = "ls | sort-by modified | reduce --fold 0 {|it, acc|\n  $acc + $it.size\n} | into string";
+        let config = Config {
+            max_width: 40,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "ls\n  | sort-by modified\n  | reduce --fold 0 {|it, acc|\n    $acc + $it.size\n  }\n  | into string\n"
+        );
+    }
+
+    #[test]
+    fn test_quote_style_leaves_strings_inside_interpolations() {
+        let source = concat!(
+            "let a = $\"x ('a' + 'b') y\"\n",
+            "let b = $\"n: ({ 'k': 1 } | get k)\"\n",
+            "let c = $\"t: ([ [ 'h' ]; [ 'v' ] ] | length)\"\n",
+            "let d = $\"a ($'b') c\"\n",
+            "let e = $'x (\"a\" + \"b\") y'\n",
+        );
+        for quote_style in [QuoteStyle::Single, QuoteStyle::Double] {
+            let config = Config {
+                quote_style,
+                ..Default::default()
+            };
+            assert_eq!(format_source(source, &config).unwrap(), source);
+        }
+    }
+
+    #[test]
+    fn test_record_alignment_uses_display_width() {
+        let source = "{\n名前: 1\nabc: 2\ncafé: 3\n}";
+        let config = Config {
+            align_record_values: true,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(result, "{\n  名前: 1,\n  abc:  2,\n  café: 3,\n}\n");
+    }
+
+    #[test]
+    fn test_table_columns_aligned_by_display_width() {
+        let source = "[\n[name, size];\n[東京, 1]\n[café, 22]\n[abc, 3]\n]";
+        let config = Config {
+            bracket_spacing: BracketSpacing::Compact,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "[\n  [name, size];\n  [東京, 1],\n  [café, 22],\n  [abc,  3],\n]\n"
+        );
+    }
+
+    #[test]
+    fn test_match_arrows_aligned_by_display_width() {
+        let source = "match $x {\n\"é\" => 1\n\"東京\" => 2\n_ => 3\n}";
+        let config = Config {
+            align_match_arrows: true,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "match $x {\n  \"é\"    => 1\n  \"東京\" => 2\n  _      => 3\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_pipeline_in_expanded_body_measured_from_its_indent() {
+        let source = "def some_function_name [first: string, second: int] { ls | where size > 10kb | sort-by name | first 3 | get name }";
+        let config = Config {
+            block_layout: BlockLayout::Auto,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "def some_function_name [first: string, second: int] {\n  ls | where size > 10kb | sort-by name | first 3 | get name\n}\n"
+        );
+        assert_eq!(format_source(&result, &config).unwrap(), result);
+    }
+
+    #[test]
+    fn test_chain_in_expanded_body_is_idempotent() {
+        let source = "def greet [name: string, --loud] { if $loud { print ($name | str upcase) } else { print $name } }";
+        let config = Config {
+            block_layout: BlockLayout::Auto,
+            max_width: 80,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(
+            result,
+            "def greet [name: string, --loud] {\n  if $loud { print ($name | str upcase) } else { print $name }\n}\n"
+        );
+        assert_eq!(format_source(&result, &config).unwrap(), result);
+    }
+
+    #[test]
+    fn test_nested_collections_are_idempotent() {
+        let source = "let config = { history: { max_size: 100000, file_format: \"sqlite\", sync_on_enter: true, isolation: false }, show_banner: false }";
+        let expected = "let config = {\n  history: { max_size: 100000, file_format: \"sqlite\", sync_on_enter: true, isolation: false },\n  show_banner: false,\n}\n";
+        for collection_layout in [
+            CollectionLayout::Preserve,
+            CollectionLayout::Auto,
+            CollectionLayout::Fill,
+        ] {
+            let config = Config {
+                collection_layout,
+                ..Default::default()
+            };
+            let result = format_source(source, &config).unwrap();
+            assert_eq!(result, expected);
+            assert_eq!(format_source(&result, &config).unwrap(), result);
+        }
+
+        // A multiline list opening on its record's line
+        let source = "let x = {b: [\n 1\n]}";
+        let cases = [
+            (
+                CollectionLayout::Preserve,
+                "let x = {\n  b: [\n    1,\n  ],\n}\n",
+            ),
+            (CollectionLayout::Auto, "let x = { b: [ 1 ] }\n"),
+            (CollectionLayout::Fill, "let x = { b: [ 1 ] }\n"),
+        ];
+        for (collection_layout, expected) in cases {
+            let config = Config {
+                collection_layout,
+                ..Default::default()
+            };
+            let result = format_source(source, &config).unwrap();
+            assert_eq!(result, expected);
+            assert_eq!(format_source(&result, &config).unwrap(), result);
+        }
+    }
+
+    #[test]
+    fn test_block_layout_auto_is_idempotent() {
+        let config = Config {
+            block_layout: BlockLayout::Auto,
+            ..Default::default()
+        };
+        let cases = [
+            (
+                "match $in { 1 | 2 => \"small\", 3 => \"three\", _ => { print \"other\"; \"big\" } }",
+                "match $in {\n  1 | 2 => \"small\"\n  3 => \"three\"\n  _ => {\n    print \"other\"\n    \"big\"\n  }\n}\n",
+            ),
+            (
+                "if $x > 1 { \"big\" } else { print \"small\"; 0 }",
+                "if $x > 1 {\n  \"big\"\n} else {\n  print \"small\"\n  0\n}\n",
+            ),
+            (
+                "try { open config.toml } catch { print \"missing\"; {} }",
+                "try {\n  open config.toml\n} catch {\n  print \"missing\"\n  {}\n}\n",
+            ),
+        ];
+        for (source, expected) in cases {
+            let result = format_source(source, &config).unwrap();
+            assert_eq!(result, expected);
+            assert_eq!(format_source(&result, &config).unwrap(), result);
+        }
+    }
+
+    #[test]
+    fn test_block_fit_measures_normalized_params() {
+        let source = "let closure = {|x,y| $x + $y}";
+        let config = Config {
+            block_layout: BlockLayout::Auto,
+            max_width: 30,
+            ..Default::default()
+        };
+        let result = format_source(source, &config).unwrap();
+        assert_eq!(result, "let closure = {|x, y|\n  $x + $y\n}\n");
+        assert_eq!(format_source(&result, &config).unwrap(), result);
+    }
+
+    #[test]
+    fn test_table_cells_holding_collections_are_formatted() {
+        let source = "[[a, b]; [1, {x: 1}], [2, [1 2 3]]]";
+        let result = format_source(source, &Config::default()).unwrap();
+        assert_eq!(result, "[[a, b]; [1, { x: 1 }], [2, [ 1, 2, 3 ]]]\n");
+        assert_eq!(format_source(&result, &Config::default()).unwrap(), result);
+    }
+
+    #[test]
+    fn test_aligned_table_rows_may_fill_max_width() {
+        let source = "[[name, size]; [a, 1], [a-much-longer-name, 22]]";
+        let config = |max_width| Config {
+            max_width,
+            ..Default::default()
+        };
+        // The header row ends exactly at column 31, counting its `;`
+        assert_eq!(
+            format_source(source, &config(31)).unwrap(),
+            "[\n  [ name,               size ];\n  [ a,                  1 ],\n  [ a-much-longer-name, 22 ],\n]\n"
+        );
+        assert_eq!(
+            format_source(source, &config(30)).unwrap(),
+            "[\n  [ name, size ];\n  [ a, 1 ],\n  [ a-much-longer-name, 22 ],\n]\n"
+        );
+    }
+
+    #[test]
+    fn test_line_widths_count_display_columns() {
+        // Each of these takes fewer than 100 columns but more than 100 bytes
+        let name = "東京".repeat(14);
+        let source = format!("ls | where name == \"{name}\" | get size | first 3\n");
+        assert_eq!(format_source(&source, &Config::default()).unwrap(), source);
+
+        let name = "東京".repeat(18);
+        let source = format!("ls | each {{|f|\n  print \"{name}\"\n}}");
+        let config = Config {
+            block_layout: BlockLayout::Auto,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_source(&source, &config).unwrap(),
+            format!("ls | each {{|f| print \"{name}\" }}\n")
+        );
+    }
+
+    #[test]
+    fn test_external_command_keeps_its_caret() {
+        let source =
+            "ls | ^grep x; ^ls\nif true { ^cp a b } else { ^mv a b }\ndo {\n  ^cp a b\n}\n";
+        assert_eq!(format_source(source, &Config::default()).unwrap(), source);
+    }
 }
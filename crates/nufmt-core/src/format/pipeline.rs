@@ -0,0 +1,95 @@
+//! Pipeline discovery for width-aware pipeline breaking.
+
+use std::ops::Range;
+
+use nu_protocol::{
+    BlockId,
+    ast::Block,
+    engine::{EngineState, StateWorkingSet},
+};
+use unicode_width::UnicodeWidthStr;
+
+/// A pipeline with two or more stages, located by byte offsets in the source.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    /// Byte range of each stage, in source order.
+    pub stages: Vec<Range<usize>>,
+    /// Start offsets of the `|` tokens between stages.
+    pub pipes: Vec<usize>,
+}
+
+impl Pipeline {
+    /// Start offset of the first stage.
+    pub fn start(&self) -> usize {
+        self.stages.first().map_or(0, |s| s.start)
+    }
+
+    /// End offset of the last stage.
+    pub fn end(&self) -> usize {
+        self.stages.last().map_or(0, |s| s.end)
+    }
+}
+
+/// Collect every multi-stage pipeline in the parsed source.
+///
+/// Nested blocks (closures, `def` bodies, subexpressions) are stored in the
+/// working set rather than the top-level block, so those are walked as well.
+pub fn collect_pipelines(
+    engine_state: &EngineState,
+    working_set: &StateWorkingSet,
+    block: &Block,
+    source: &str,
+) -> Vec<Pipeline> {
+    let nested = (engine_state.num_blocks()..working_set.num_blocks())
+        .map(|id| working_set.get_block(BlockId::new(id)).as_ref());
+
+    let mut pipelines: Vec<Pipeline> = std::iter::once(block)
+        .chain(nested)
+        .flat_map(|b| &b.pipelines)
+        .filter(|p| p.elements.len() > 1)
+        .filter_map(|p| {
+            let mut stages = Vec::with_capacity(p.elements.len());
+            let mut pipes = Vec::with_capacity(p.elements.len() - 1);
+            for element in &p.elements {
+                let span = element.expr.span;
+                if span.start > span.end || span.end > source.len() {
+                    return None;
+                }
+                if let Some(pipe) = element.pipe {
+                    // Nushell can't parse a line break at a redirecting pipe
+                    // like `e>|`, so those pipelines are left alone
+                    if source.get(pipe.start..pipe.end) != Some("|")
+                        || source[..pipe.start].ends_with('>')
+                    {
+                        return None;
+                    }
+                    pipes.push(pipe.start);
+                }
+                stages.push(span.start..span.end);
+            }
+            (pipes.len() + 1 == stages.len()).then_some(Pipeline { stages, pipes })
+        })
+        .collect();
+
+    pipelines.sort_by_key(Pipeline::start);
+    pipelines
+}
+
+/// Display width of the first line of `text` with whitespace runs collapsed
+/// to one space.
+///
+/// Returns the width and whether `text` continues onto further lines.
+pub fn flat_width(text: &str) -> (usize, bool) {
+    let text = text.trim();
+    let (first_line, multiline) = text
+        .split_once('\n')
+        .map_or((text, false), |(line, _)| (line, true));
+
+    let width = first_line
+        .split_whitespace()
+        .map(UnicodeWidthStr::width)
+        .sum::<usize>()
+        + first_line.split_whitespace().count().saturating_sub(1);
+
+    (width, multiline)
+}
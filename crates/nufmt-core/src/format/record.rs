@@ -0,0 +1,100 @@
+//! Alignment of record values.
+
+use std::collections::HashMap;
+
+use nu_protocol::{
+    ast::{Block, Expr, Expression, RecordItem, Traverse},
+    engine::StateWorkingSet,
+};
+use unicode_width::UnicodeWidthStr;
+
+/// Keys more than this many columns wider than the narrowest key in their
+/// group are left out of the alignment, so they don't push it to the right.
+const MAX_PADDING: usize = 16;
+
+/// Compute how far each record key must be padded to line up the values of
+/// its group.
+///
+/// A group is a run of `key: value` entries not separated by a blank line, a
+/// comment on its own line, or a `...` spread. Keys are measured in display
+/// columns as rendered in `keys`, falling back to the source. The result maps the end offset of
+/// each key to its padding in columns.
+pub fn collect_key_padding(
+    working_set: &StateWorkingSet,
+    block: &Block,
+    source: &str,
+    keys: &HashMap<usize, String>,
+) -> HashMap<usize, usize> {
+    let mut found = Vec::new();
+    block.flat_map(
+        working_set,
+        &|expr: &Expression| record_padding(expr, source, keys),
+        &mut found,
+    );
+    found.into_iter().collect()
+}
+
+/// Compute the key padding of a single record expression.
+fn record_padding(
+    expr: &Expression,
+    source: &str,
+    keys: &HashMap<usize, String>,
+) -> Vec<(usize, usize)> {
+    let Expr::Record(items) = &expr.expr else {
+        return Vec::new();
+    };
+
+    let mut padding = Vec::new();
+    let mut group = Vec::new();
+    let mut prev_end = expr.span.start + 1;
+
+    for item in items {
+        match item {
+            RecordItem::Pair(key, value) => {
+                let between = source.get(prev_end..key.span.start).unwrap_or_default();
+                if starts_group(between) {
+                    pad_group(&mut group, &mut padding);
+                }
+                let key_text = keys.get(&key.span.start).map_or_else(
+                    || source.get(key.span.start..key.span.end).unwrap_or_default(),
+                    String::as_str,
+                );
+                group.push((key.span.end, key_text.width()));
+                prev_end = value.span.end;
+            }
+            RecordItem::Spread(_, value) => {
+                pad_group(&mut group, &mut padding);
+                prev_end = value.span.end;
+            }
+        }
+    }
+    pad_group(&mut group, &mut padding);
+
+    padding
+}
+
+/// Whether the text between two entries separates them into different groups.
+fn starts_group(between: &str) -> bool {
+    let lines: Vec<&str> = between.split('\n').collect();
+    lines[1..].iter().any(|line| line.contains('#'))
+        || lines.len() > 2
+            && lines[1..lines.len() - 1]
+                .iter()
+                .any(|line| line.trim().trim_matches(',').trim().is_empty())
+}
+
+/// Pad the keys of `group` to a common width and start a new group.
+fn pad_group(group: &mut Vec<(usize, usize)>, padding: &mut Vec<(usize, usize)>) {
+    let narrowest = group.iter().map(|&(_, w)| w).min().unwrap_or(0);
+    let width = group
+        .iter()
+        .map(|&(_, w)| w)
+        .filter(|&w| w <= narrowest + MAX_PADDING)
+        .max()
+        .unwrap_or(0);
+    padding.extend(
+        group
+            .drain(..)
+            .map(|(end, w)| (end, width.saturating_sub(w))),
+    );
+}
@@ -0,0 +1,35 @@
+//! Normalization of redirection operators like `o>`, `err>>`, and `o+e>|`.
+
+use crate::RedirectionStyle;
+
+/// Rewrite a redirection operator in the configured style.
+///
+/// `out>` and `o>` become each other, as do `err>` and `e>`, and every
+/// combination of both streams becomes `o+e>` or `out+err>`. The `>`, `>>`
+/// and `>|` endings are kept. Operators that aren't recognized are returned
+/// as written.
+pub fn normalize_redirection(text: &str, style: RedirectionStyle) -> String {
+    let short = match style {
+        RedirectionStyle::Preserve => return text.to_string(),
+        RedirectionStyle::Short => true,
+        RedirectionStyle::Long => false,
+    };
+    let Some(arrow) = text.find('>') else {
+        return text.to_string();
+    };
+    let (streams, ending) = text.split_at(arrow);
+    if !matches!(ending, ">" | ">>" | ">|") {
+        return text.to_string();
+    }
+
+    let streams = match streams {
+        "o" | "out" if short => "o",
+        "o" | "out" => "out",
+        "e" | "err" if short => "e",
+        "e" | "err" => "err",
+        "o+e" | "e+o" | "out+err" | "err+out" if short => "o+e",
+        "o+e" | "e+o" | "out+err" | "err+out" => "out+err",
+        _ => return text.to_string(),
+    };
+    format!("{streams}{ending}")
+}
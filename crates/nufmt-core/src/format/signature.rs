@@ -0,0 +1,417 @@
+//! Parsing for `def` signatures and other parameter lists.
+//!
+//! A signature arrives as a single token (`[x: int, --flag(-f)]: string -> int`),
+//! so its parameter list is split up here to be laid out by the formatter.
+//! Closure parameters (`|x: int, y|`) share the same syntax and parser.
+
+/// A parsed `def` signature.
+#[derive(Debug, Clone)]
+pub struct Signature<'a> {
+    /// The parameter list between `[` and `]`.
+    pub list: ParamList<'a>,
+    /// Text after the closing `]` (input/output types).
+    pub rest: &'a str,
+    /// Parsed input/output types, if `rest` has any and they're well-formed.
+    pub io: Option<IoTypes>,
+}
+
+/// A parameter list without its delimiters.
+#[derive(Debug, Clone)]
+pub struct ParamList<'a> {
+    /// Parameters in source order.
+    pub params: Vec<Param<'a>>,
+    /// Comments after the last parameter.
+    pub trailing_comments: Vec<&'a str>,
+    /// Whether the source had a line break inside the list.
+    pub multiline: bool,
+}
+
+impl ParamList<'_> {
+    /// Whether any comment appears inside the parameter list.
+    pub fn has_comments(&self) -> bool {
+        !self.trailing_comments.is_empty()
+            || self
+                .params
+                .iter()
+                .any(|p| p.comment.is_some() || !p.comments.is_empty())
+    }
+}
+
+/// A single parameter, flag, or rest parameter.
+#[derive(Debug, Clone, Default)]
+pub struct Param<'a> {
+    /// Comments on their own lines before the parameter.
+    pub comments: Vec<&'a str>,
+    /// Name including its markers: `x`, `x?`, `...rest`, `--flag(-f)`.
+    pub name: &'a str,
+    /// Type annotation after `:`.
+    pub ty: Option<&'a str>,
+    /// Default value after `=`.
+    pub default: Option<&'a str>,
+    /// Comment at the end of the parameter's line.
+    pub comment: Option<&'a str>,
+}
+
+impl Param<'_> {
+    /// Render the parameter as `name: type = default`.
+    pub fn render(&self) -> String {
+        let mut out = self.name.to_string();
+        if let Some(ty) = self.ty {
+            out.push_str(": ");
+            out.push_str(&normalize_type(ty));
+        }
+        if let Some(default) = self.default {
+            out.push_str(" = ");
+            out.push_str(default);
+        }
+        out
+    }
+}
+
+/// Input/output types after a parameter list: `: string -> int`.
+#[derive(Debug, Clone)]
+pub struct IoTypes {
+    /// Each signature as a normalized `input -> output` string.
+    pub entries: Vec<String>,
+    /// Whether the types were written as a bracketed list.
+    pub list: bool,
+    /// Whether the source list spanned multiple lines.
+    pub multiline: bool,
+}
+
+impl IoTypes {
+    /// Render on one line, including the leading `: `.
+    pub fn render(&self) -> String {
+        if self.list {
+            format!(": [{}]", self.entries.join(", "))
+        } else {
+            format!(": {}", self.entries.join(", "))
+        }
+    }
+}
+
+/// Lexical pieces of a parameter list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lexeme<'a> {
+    /// A name, type, or default value.
+    Word(&'a str),
+    Colon,
+    Equals,
+    Comma,
+    Newline,
+    Comment(&'a str),
+}
+
+/// What the next word in a parameter list fills in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expect {
+    Type,
+    Default,
+}
+
+/// Parse a signature token like `[x: int, y = 1]: string -> int`.
+///
+/// Returns `None` for anything that doesn't look like a well-formed parameter
+/// list, so the caller can leave the text as written.
+pub fn parse_signature(text: &str) -> Option<Signature<'_>> {
+    let inner = text.strip_prefix('[')?;
+    let (list, close) = parse_params(inner, ']')?;
+    let rest = &inner[close + 1..];
+
+    Some(Signature {
+        list,
+        rest,
+        io: parse_io_types(rest),
+    })
+}
+
+/// Parse the parameters after an opening delimiter, up to `close` (`]` or `|`).
+///
+/// Returns the parameters and the byte offset of the closing delimiter.
+pub fn parse_params(text: &str, close: char) -> Option<(ParamList<'_>, usize)> {
+    let (lexemes, close) = lex(text, close)?;
+    let multiline = text[..close].contains('\n');
+
+    let mut params: Vec<Param> = Vec::new();
+    let mut pending_comments = Vec::new();
+    let mut expect = None;
+    // Whether the last parameter was closed by a comma.
+    let mut closed = true;
+    // Whether the last parameter started on the current line.
+    let mut param_on_line = false;
+
+    for lexeme in lexemes {
+        match lexeme {
+            Lexeme::Newline => param_on_line = false,
+            Lexeme::Comment(comment) => match params.last_mut() {
+                Some(last) if param_on_line && expect.is_none() && last.comment.is_none() => {
+                    last.comment = Some(comment);
+                }
+                _ => pending_comments.push(comment),
+            },
+            Lexeme::Comma => {
+                if expect.is_some() || closed {
+                    return None;
+                }
+                closed = true;
+            }
+            Lexeme::Colon => {
+                let last = params.last()?;
+                if closed || expect.is_some() || last.ty.is_some() || last.default.is_some() {
+                    return None;
+                }
+                expect = Some(Expect::Type);
+            }
+            Lexeme::Equals => {
+                let last = params.last()?;
+                if closed || expect.is_some() || last.default.is_some() {
+                    return None;
+                }
+                expect = Some(Expect::Default);
+            }
+            Lexeme::Word(word) => match expect.take() {
+                Some(Expect::Type) => params.last_mut()?.ty = Some(word),
+                Some(Expect::Default) => params.last_mut()?.default = Some(word),
+                None => {
+                    // A parenthesized short flag separated from its long name
+                    if word.starts_with('(') {
+                        return None;
+                    }
+                    params.push(Param {
+                        comments: std::mem::take(&mut pending_comments),
+                        name: word,
+                        ..Param::default()
+                    });
+                    closed = false;
+                    param_on_line = true;
+                }
+            },
+        }
+    }
+
+    if expect.is_some() {
+        return None;
+    }
+
+    let list = ParamList {
+        params,
+        trailing_comments: pending_comments,
+        multiline,
+    };
+    Some((list, close))
+}
+
+/// Parse input/output types like `: string -> int` or `: [string -> int, nothing -> int]`.
+///
+/// Returns `None` if there are no types or they contain anything unexpected,
+/// such as comments.
+fn parse_io_types(rest: &str) -> Option<IoTypes> {
+    let types = rest.trim().strip_prefix(':')?.trim();
+    if types.contains('#') {
+        return None;
+    }
+
+    let (inner, list) = match types.strip_prefix('[') {
+        Some(inner) => (inner.strip_suffix(']')?, true),
+        None => (types, false),
+    };
+
+    let entries: Vec<String> = split_top_level(inner, |c| c == ',' || c == '\n')
+        .into_iter()
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let arrow = find_arrow(entry)?;
+            let input = normalize_type(&entry[..arrow]);
+            let output = normalize_type(&entry[arrow + "->".len()..]);
+            (!input.is_empty() && !output.is_empty()).then(|| format!("{input} -> {output}"))
+        })
+        .collect::<Option<_>>()?;
+
+    if entries.is_empty() || (!list && entries.len() > 1) {
+        return None;
+    }
+
+    Some(IoTypes {
+        entries,
+        list,
+        multiline: inner.contains('\n'),
+    })
+}
+
+/// Normalize spacing in a type: `record<a:int,b: string>` becomes `record<a: int, b: string>`.
+///
+/// Whitespace is dropped, then one space is added after each `,` and `:`.
+/// Quoted record keys are copied as written.
+pub fn normalize_type(ty: &str) -> String {
+    let mut out = String::with_capacity(ty.len());
+    let mut quote = None;
+
+    for c in ty.trim().chars() {
+        if let Some(q) = quote {
+            out.push(c);
+            if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' | '`' => {
+                quote = Some(c);
+                out.push(c);
+            }
+            ',' | ':' => {
+                out.push(c);
+                out.push(' ');
+            }
+            c if c.is_whitespace() => {}
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+/// Split `text` at separators that aren't nested inside brackets or quotes.
+fn split_top_level(text: &str, is_separator: impl Fn(char) -> bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut prev = '\0';
+    let mut start = 0;
+
+    for (i, c) in text.char_indices() {
+        if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            }
+        } else {
+            match c {
+                '"' | '\'' | '`' => quote = Some(c),
+                '(' | '[' | '{' | '<' => depth += 1,
+                '>' if prev == '-' => {}
+                ')' | ']' | '}' | '>' => depth = depth.saturating_sub(1),
+                c if depth == 0 && is_separator(c) => {
+                    parts.push(&text[start..i]);
+                    start = i + c.len_utf8();
+                }
+                _ => {}
+            }
+        }
+        prev = c;
+    }
+
+    parts.push(&text[start..]);
+    parts
+}
+
+/// Find the byte offset of a top-level `->` in a type signature.
+fn find_arrow(text: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '-' if depth == 0 && chars.peek().is_some_and(|&(_, next)| next == '>') => {
+                return Some(i);
+            }
+            '<' | '(' | '[' | '{' => depth += 1,
+            '>' | ')' | ']' | '}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Split the text after an opening delimiter into lexemes, up to `close`.
+///
+/// Returns the lexemes and the byte offset of the closing delimiter.
+fn lex(text: &str, close: char) -> Option<(Vec<Lexeme<'_>>, usize)> {
+    let mut lexemes = Vec::new();
+    let mut chars = text.char_indices().peekable();
+
+    while let Some(&(i, c)) = chars.peek() {
+        match c {
+            c if c == close => return Some((lexemes, i)),
+            '\n' => {
+                lexemes.push(Lexeme::Newline);
+                chars.next();
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            ',' => {
+                lexemes.push(Lexeme::Comma);
+                chars.next();
+            }
+            ':' => {
+                lexemes.push(Lexeme::Colon);
+                chars.next();
+            }
+            '=' => {
+                lexemes.push(Lexeme::Equals);
+                chars.next();
+            }
+            '#' => {
+                let end = text[i..].find('\n').map_or(text.len(), |n| i + n);
+                lexemes.push(Lexeme::Comment(text[i..end].trim_end()));
+                while chars.next_if(|&(j, _)| j < end).is_some() {}
+            }
+            _ => {
+                let end = word_end(text, i, close)?;
+                lexemes.push(Lexeme::Word(&text[i..end]));
+                while chars.next_if(|&(j, _)| j < end).is_some() {}
+            }
+        }
+    }
+
+    None
+}
+
+/// Find the end of a word starting at `start`, in a list closed by `close`.
+///
+/// Brackets and quotes are skipped as a unit, so types like
+/// `record<a: int, b: string>` and defaults like `{a: 1}` stay in one word.
+fn word_end(text: &str, start: usize, close: char) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut prev = '\0';
+
+    for (i, c) in text[start..].char_indices() {
+        let i = start + i;
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' && q == '"' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            prev = c;
+            continue;
+        }
+
+        match c {
+            '"' | '\'' | '`' => quote = Some(c),
+            '(' | '[' | '{' | '<' => depth += 1,
+            // `->` is an arrow, not a closing angle bracket
+            '>' if prev == '-' => {}
+            ')' | ']' | '}' | '>' => {
+                if depth == 0 {
+                    return (c == close).then_some(i);
+                }
+                depth -= 1;
+            }
+            '|' if depth == 0 && close == '|' => return Some(i),
+            c if depth == 0 && (c.is_whitespace() || matches!(c, ',' | ':' | '=')) => {
+                return Some(i);
+            }
+            _ => {}
+        }
+        prev = c;
+    }
+
+    (depth == 0 && quote.is_none()).then_some(text.len())
+}
@@ -1,44 +1,306 @@
 //! String quote conversion utilities.
+//!
+//! A string is converted by reading its content into characters and escapes,
+//! then writing it back in whichever quotes need the fewest escapes, with
+//! `quote_style` breaking ties. Raw strings are never touched.
 
+use std::collections::HashMap;
+
+use nu_parser::FlatShape;
+use nu_protocol::{
+    ast::{Block, Expr, Expression, Traverse},
+    engine::StateWorkingSet,
+};
+
+use super::token::Token;
 use crate::QuoteStyle;
 
+/// Escapes that stand for a character a single-quoted string can hold as is.
+const PLAIN_ESCAPES: [char; 13] = [
+    '"', '\'', '\\', '/', '(', ')', '{', '}', '$', '^', '#', '|', '~',
+];
+
+/// Escapes that stand for a control character, which only double quotes
+/// can write.
+const CONTROL_ESCAPES: [char; 7] = ['a', 'b', 'e', 'f', 'n', 'r', 't'];
+
+/// The quotes a string can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Quote {
+    Double,
+    Single,
+    Backtick,
+}
+
+/// One piece of a string's content.
+#[derive(Debug, Clone, Copy)]
+enum Piece<'a> {
+    /// A character, written as is or escaped.
+    Char(char),
+    /// An escape for a control character, like `\n`, kept as written.
+    Control(&'a str),
+}
+
 /// Convert string quotes based on configured style.
+///
+/// Anything that isn't a plain quoted string, or whose escapes can't be
+/// read, is returned unchanged.
 pub fn convert_string_quotes(token: &str, style: QuoteStyle) -> String {
-    match style {
-        QuoteStyle::Preserve => token.to_string(),
-        QuoteStyle::Double => to_double_quotes(token),
-        QuoteStyle::Single => to_single_quotes(token),
+    if style == QuoteStyle::Preserve {
+        return token.to_string();
     }
+    let Some((quote, content)) = split_quotes(token) else {
+        return token.to_string();
+    };
+    read_pieces(content, quote)
+        .and_then(|pieces| requote(&[pieces], quote, style, false))
+        .map_or_else(
+            || token.to_string(),
+            |(quote, parts)| format!("{0}{1}{0}", mark(quote), parts.concat()),
+        )
 }
 
-/// Convert a string to double quotes if possible.
-fn to_double_quotes(token: &str) -> String {
-    if token.starts_with('"') {
-        return token.to_string();
+/// Quote plain text, like a bare record key, in whichever quotes need the
+/// fewest escapes.
+///
+/// Double quotes are preferred when `style` is `preserve`.
+pub fn quote_text(text: &str, style: QuoteStyle) -> String {
+    let pieces = [text.chars().map(Piece::Char).collect::<Vec<_>>()];
+    let preferred = preferred_quote(style);
+    let quote = best_quote(&pieces, preferred, false).unwrap_or(preferred);
+    write_pieces(&pieces[0], quote, false).map_or_else(
+        || text.to_string(),
+        |(_, content)| format!("{0}{content}{0}", mark(quote)),
+    )
+}
+
+/// Collect every string interpolation whose quotes change under
+/// `quote_style`, keyed by start offset, with its end offset and new text.
+///
+/// Interpolations are only converted when their expressions hold no quotes
+/// or backslashes, so that nothing but the literal parts needs rewriting.
+pub fn collect_interpolations(
+    working_set: &StateWorkingSet,
+    block: &Block,
+    source: &str,
+    quote_style: QuoteStyle,
+) -> HashMap<usize, (usize, String)> {
+    if quote_style == QuoteStyle::Preserve {
+        return HashMap::new();
     }
+    let mut found = Vec::new();
+    block.flat_map(
+        working_set,
+        &|expr: &Expression| {
+            interpolation(expr, source, quote_style)
+                .into_iter()
+                .collect()
+        },
+        &mut found,
+    );
+    found.into_iter().collect()
+}
 
-    if let Some(content) = token.strip_prefix('\'').and_then(|s| s.strip_suffix('\'')) {
-        if content.contains('"') || content.contains('\\') {
-            return token.to_string();
+/// Whether `offset` falls inside the expressions of a string interpolation,
+/// where strings keep their quotes.
+pub fn in_interpolation(tokens: &[Token<'_>], offset: usize) -> bool {
+    let depth = tokens
+        .iter()
+        .take_while(|t| t.start < offset)
+        .filter(|t| matches!(t.shape, FlatShape::StringInterpolation))
+        .fold(0_usize, |depth, t| {
+            if t.text.starts_with('$') {
+                depth + 1
+            } else {
+                depth.saturating_sub(1)
+            }
+        });
+    depth > 0
+}
+
+/// Rewrite a single `$"..."` or `$'...'` interpolation.
+fn interpolation(
+    expr: &Expression,
+    source: &str,
+    style: QuoteStyle,
+) -> Option<(usize, (usize, String))> {
+    let Expr::StringInterpolation(parts) = &expr.expr else {
+        return None;
+    };
+    let (start, end) = (expr.span.start, expr.span.end);
+    let text = source.get(start..end)?;
+    let (quote, _) = split_quotes(text.strip_prefix('$')?)?;
+    if quote == Quote::Backtick {
+        return None;
+    }
+
+    // The parts must cover the content exactly, and the expressions must be
+    // safe to move between quotes
+    let mut cursor = start + "$\"".len();
+    let mut literals = Vec::new();
+    let mut expressions = Vec::new();
+    for part in parts {
+        let part_text = source.get(part.span.start..part.span.end)?;
+        if part.span.start != cursor {
+            return None;
+        }
+        if matches!(part.expr, Expr::String(_)) {
+            literals.push(read_pieces(part_text, quote)?);
+        } else if part_text.contains(['"', '\'', '`', '\\']) {
+            return None;
+        } else {
+            expressions.push((literals.len(), part_text));
+            literals.push(Vec::new());
         }
-        return format!("\"{content}\"");
+        cursor = part.span.end;
+    }
+    if cursor + 1 != end {
+        return None;
     }
 
-    token.to_string()
+    let (quote, mut written) = requote(&literals, quote, style, true)?;
+    for (i, expression) in expressions {
+        written[i] = expression.to_string();
+    }
+    let text = format!("${0}{1}{0}", mark(quote), written.concat());
+    Some((start, (end, text)))
 }
 
-/// Convert a string to single quotes if possible.
-fn to_single_quotes(token: &str) -> String {
-    if token.starts_with('\'') {
-        return token.to_string();
+/// Write literal parts in the best quotes for `style`, returning those
+/// quotes and the parts without them, or `None` if the quotes stay as they
+/// are.
+fn requote(
+    parts: &[Vec<Piece<'_>>],
+    current: Quote,
+    style: QuoteStyle,
+    interpolated: bool,
+) -> Option<(Quote, Vec<String>)> {
+    if style == QuoteStyle::Preserve {
+        return None;
     }
+    let best = best_quote(parts, preferred_quote(style), interpolated)?;
+    if best == current {
+        return None;
+    }
+
+    let written = parts
+        .iter()
+        .map(|pieces| write_pieces(pieces, best, interpolated).map(|(_, text)| text))
+        .collect::<Option<Vec<_>>>()?;
+    Some((best, written))
+}
+
+/// The quotes, out of `preferred` and the other kind, that write `parts`
+/// with the fewest escapes, favouring `preferred` on a tie.
+fn best_quote(parts: &[Vec<Piece<'_>>], preferred: Quote, interpolated: bool) -> Option<Quote> {
+    let cost = |quote: Quote| {
+        parts
+            .iter()
+            .map(|pieces| write_pieces(pieces, quote, interpolated).map(|(escapes, _)| escapes))
+            .sum::<Option<usize>>()
+    };
+    [preferred, other_quote(preferred)]
+        .into_iter()
+        .filter_map(|quote| Some((cost(quote)?, quote)))
+        .min_by_key(|(escapes, _)| *escapes)
+        .map(|(_, quote)| quote)
+}
+
+/// Split a quoted string into its quotes and content.
+fn split_quotes(text: &str) -> Option<(Quote, &str)> {
+    [Quote::Double, Quote::Single, Quote::Backtick]
+        .into_iter()
+        .find_map(|quote| {
+            let content = text.strip_prefix(mark(quote))?.strip_suffix(mark(quote))?;
+            Some((quote, content))
+        })
+}
 
-    if let Some(content) = token.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
-        if content.contains('\'') || content.contains('\\') {
-            return token.to_string();
+/// Read the content of a string written in `quote` into pieces.
+///
+/// Only double quotes have escapes. An escape the parser wouldn't accept,
+/// or a bare quote mark, gives `None`.
+fn read_pieces(content: &str, quote: Quote) -> Option<Vec<Piece<'_>>> {
+    if quote != Quote::Double {
+        return (!content.contains(mark(quote)))
+            .then(|| content.chars().map(Piece::Char).collect());
+    }
+    let mut pieces = Vec::new();
+    let mut chars = content.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == '"' {
+            return None;
+        }
+        if c != '\\' {
+            pieces.push(Piece::Char(c));
+            continue;
+        }
+        let (_, escaped) = chars.next()?;
+        if PLAIN_ESCAPES.contains(&escaped) {
+            pieces.push(Piece::Char(escaped));
+        } else if CONTROL_ESCAPES.contains(&escaped) {
+            pieces.push(Piece::Control(&content[i..i + 2]));
+        } else if escaped == 'u' && chars.next_if(|&(_, c)| c == '{').is_some() {
+            let close = content[i..].find('}')? + i;
+            chars.find(|&(j, _)| j == close)?;
+            pieces.push(Piece::Control(&content[i..=close]));
+        } else {
+            return None;
         }
-        return format!("'{content}'");
     }
+    Some(pieces)
+}
 
-    token.to_string()
+/// Write pieces in `quote`, returning the number of escapes and the text,
+/// or `None` if the quote can't hold them.
+fn write_pieces(pieces: &[Piece<'_>], quote: Quote, interpolated: bool) -> Option<(usize, String)> {
+    let mut escapes = 0;
+    let mut text = String::new();
+    for piece in pieces {
+        match (*piece, quote) {
+            (Piece::Control(escape), Quote::Double) => text.push_str(escape),
+            (Piece::Char(c @ ('"' | '\\')), Quote::Double) => {
+                escapes += 1;
+                text.push('\\');
+                text.push(c);
+            }
+            (Piece::Char('('), Quote::Double) if interpolated => {
+                escapes += 1;
+                text.push_str("\\(");
+            }
+            (Piece::Char(c), Quote::Double) => text.push(c),
+            (Piece::Char(c), Quote::Single | Quote::Backtick)
+                if c != mark(quote) && !(interpolated && c == '(') =>
+            {
+                text.push(c);
+            }
+            _ => return None,
+        }
+    }
+    Some((escapes, text))
+}
+
+/// The quotes `style` asks for, double when it preserves them.
+const fn preferred_quote(style: QuoteStyle) -> Quote {
+    match style {
+        QuoteStyle::Single => Quote::Single,
+        QuoteStyle::Double | QuoteStyle::Preserve => Quote::Double,
+    }
+}
+
+/// The quote to fall back on when `quote` can't hold a string.
+const fn other_quote(quote: Quote) -> Quote {
+    match quote {
+        Quote::Double => Quote::Single,
+        Quote::Single | Quote::Backtick => Quote::Double,
+    }
+}
+
+/// The character that opens and closes `quote`.
+const fn mark(quote: Quote) -> char {
+    match quote {
+        Quote::Double => '"',
+        Quote::Single => '\'',
+        Quote::Backtick => '`',
+    }
 }
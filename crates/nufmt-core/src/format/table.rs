@@ -0,0 +1,237 @@
+//! Discovery and layout of table literals.
+//!
+//! A table literal `[[a, b]; [1, 2], [3, 4]]` flattens into `Table` tokens
+//! that mix brackets, separators, and whitespace, so its cells are located
+//! from the parsed AST instead.
+
+use std::{collections::HashMap, ops::Range};
+
+use nu_parser::FlatShape;
+use nu_protocol::{
+    ast::{Block, Expr, Expression, Traverse},
+    engine::StateWorkingSet,
+};
+use unicode_width::UnicodeWidthStr;
+
+use super::{
+    string::{convert_string_quotes, in_interpolation},
+    token::Token,
+};
+use crate::QuoteStyle;
+
+/// A table literal, located by byte offsets in the source.
+#[derive(Debug, Clone)]
+pub struct TableLiteral {
+    /// Offset of the opening `[`.
+    pub open: usize,
+    /// Offset of the closing `]`.
+    pub close: usize,
+    /// Byte ranges of the cells of each row, header first.
+    pub rows: Vec<Vec<Range<usize>>>,
+    /// Whether comments sit between the cells.
+    pub comments: bool,
+}
+
+impl TableLiteral {
+    /// Render the cells of each row, converting string literals to
+    /// `quote_style` and rewriting the number literals in `literals`.
+    ///
+    /// Strings keep their quotes in a table inside a string interpolation.
+    pub fn cells(
+        &self,
+        source: &str,
+        tokens: &[Token<'_>],
+        literals: &HashMap<usize, (usize, String)>,
+        quote_style: QuoteStyle,
+    ) -> Vec<Vec<String>> {
+        let quote_style = if in_interpolation(tokens, self.open) {
+            QuoteStyle::Preserve
+        } else {
+            quote_style
+        };
+        self.rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|cell| {
+                        let text = &source[cell.clone()];
+                        if let Some((_, written)) = literals
+                            .get(&cell.start)
+                            .filter(|(end, _)| *end == cell.end)
+                        {
+                            return written.clone();
+                        }
+                        // Tokens are sorted by start
+                        let first = tokens.partition_point(|t| t.start < cell.start);
+                        let is_string = tokens[first..]
+                            .iter()
+                            .take_while(|t| t.start == cell.start)
+                            .any(|t| {
+                                t.text.len() == cell.len() && matches!(t.shape, FlatShape::String)
+                            });
+                        if is_string {
+                            convert_string_quotes(text, quote_style)
+                        } else {
+                            text.to_string()
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Collect every table literal whose cells each sit on one line.
+///
+/// Tables with multiline cells, or cells holding collections or blocks, are
+/// skipped so they are formatted token by token.
+pub fn collect_tables(
+    working_set: &StateWorkingSet,
+    block: &Block,
+    source: &str,
+) -> Vec<TableLiteral> {
+    let mut found = Vec::new();
+    block.flat_map(
+        working_set,
+        &|expr: &Expression| table_literal(expr, source).into_iter().collect(),
+        &mut found,
+    );
+    found.sort_by_key(|t: &TableLiteral| t.open);
+    found
+}
+
+/// Keep only the tables whose opening bracket starts a `Table` token.
+pub fn retain_token_tables(tables: Vec<TableLiteral>, tokens: &[Token<'_>]) -> Vec<TableLiteral> {
+    tables
+        .into_iter()
+        .filter(|t| {
+            let first = tokens.partition_point(|tok| tok.start < t.open);
+            tokens[first..]
+                .iter()
+                .take_while(|tok| tok.start == t.open)
+                .any(|tok| matches!(tok.shape, FlatShape::Table))
+        })
+        .collect()
+}
+
+/// Build a `TableLiteral` from a table expression.
+fn table_literal(expr: &Expression, source: &str) -> Option<TableLiteral> {
+    let Expr::Table(table) = &expr.expr else {
+        return None;
+    };
+    let span = expr.span;
+    if table.columns.is_empty()
+        || table.rows.is_empty()
+        || span.end <= span.start
+        || source.get(span.start..=span.start) != Some("[")
+        || source.get(span.end - 1..span.end) != Some("]")
+    {
+        return None;
+    }
+    let (open, close) = (span.start, span.end - 1);
+
+    // Cells holding collections or blocks are laid out like any other
+    if std::iter::once(&table.columns)
+        .chain(&table.rows)
+        .flatten()
+        .any(is_nested)
+    {
+        return None;
+    }
+
+    let rows: Vec<Vec<Range<usize>>> = std::iter::once(&table.columns)
+        .chain(&table.rows)
+        .map(|row| {
+            row.iter()
+                .map(|cell| cell.span.start..cell.span.end)
+                .collect()
+        })
+        .collect();
+    if rows.iter().any(Vec::is_empty) {
+        return None;
+    }
+
+    // Everything between cells must be brackets, separators, and comments
+    let mut cursor = open;
+    let mut comments = false;
+    for (r, row) in rows.iter().enumerate() {
+        for (c, cell) in row.iter().enumerate() {
+            let expected = match (r, c) {
+                (0, 0) => "[[",
+                (1, 0) => "];[",
+                (_, 0) => "][",
+                _ => "",
+            };
+            let between = source.get(cursor..cell.start)?;
+            comments |= between.contains('#');
+            if separator(between) != expected || source.get(cell.clone())?.contains('\n') {
+                return None;
+            }
+            cursor = cell.end;
+        }
+    }
+    let end = source.get(cursor..=close)?;
+    comments |= end.contains('#');
+    (separator(end) == "]]").then_some(TableLiteral {
+        open,
+        close,
+        rows,
+        comments,
+    })
+}
+
+/// Whether a cell holds a collection, block, or subexpression, whose
+/// contents need formatting of their own.
+fn is_nested(cell: &Expression) -> bool {
+    match &cell.expr {
+        Expr::Record(_)
+        | Expr::List(_)
+        | Expr::Table(_)
+        | Expr::Closure(_)
+        | Expr::Block(_)
+        | Expr::Subexpression(_) => true,
+        Expr::FullCellPath(path) => is_nested(&path.head),
+        _ => false,
+    }
+}
+
+/// Strip whitespace, commas, and comments from the text between two cells.
+fn separator(text: &str) -> String {
+    text.lines()
+        .flat_map(|line| line.split('#').next().unwrap_or_default().chars())
+        .filter(|c| !c.is_whitespace() && *c != ',')
+        .collect()
+}
+
+/// Render one row as `[a, b, c]`.
+///
+/// With `widths`, every cell but the last is padded so that the columns of
+/// consecutive rows line up on screen.
+pub fn render_row(cells: &[String], widths: Option<&[usize]>, spaced: bool) -> String {
+    let mut row = String::from(if spaced { "[ " } else { "[" });
+    for (i, cell) in cells.iter().enumerate() {
+        row.push_str(cell);
+        if i + 1 < cells.len() {
+            row.push(',');
+            let pad = widths.map_or(0, |w| w[i].saturating_sub(cell.width()));
+            row.extend(std::iter::repeat_n(' ', pad + 1));
+        }
+    }
+    row.push_str(if spaced { " ]" } else { "]" });
+    row
+}
+
+/// Width of each column in display columns: the widest cell in it across all
+/// rows.
+pub fn column_widths(rows: &[Vec<String>]) -> Vec<usize> {
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    (0..columns)
+        .map(|c| {
+            rows.iter()
+                .filter_map(|row| row.get(c))
+                .map(|cell| cell.width())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect()
+}
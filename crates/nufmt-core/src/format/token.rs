@@ -12,11 +12,13 @@ pub struct Token<'a> {
     pub shape: FlatShape,
     /// The gap (whitespace/comments) before this token.
     pub gap_before: &'a str,
+    /// Byte offset of the token in the source.
+    pub start: usize,
 }
 
 /// Preprocess flattened tokens into a more convenient format.
 pub fn preprocess_tokens<'a>(source: &'a str, flattened: &[(Span, FlatShape)]) -> Vec<Token<'a>> {
-    let mut tokens = Vec::with_capacity(flattened.len());
+    let mut tokens: Vec<Token<'a>> = Vec::with_capacity(flattened.len());
     let mut last_end = 0;
 
     for (span, shape) in flattened {
@@ -24,13 +26,29 @@ pub fn preprocess_tokens<'a>(source: &'a str, flattened: &[(Span, FlatShape)]) -
             continue;
         }
 
-        let gap_before = &source[last_end..span.start];
-        let text = &source[span.start..span.end];
+        // The `^` of an external command belongs to no span, so it's left in
+        // the gap or in the text of a block's opening token
+        let start =
+            if matches!(shape, FlatShape::External(_)) && source[..span.start].ends_with('^') {
+                span.start - 1
+            } else {
+                span.start
+            };
+        if start < last_end
+            && let Some(prev) = tokens.last_mut()
+        {
+            prev.text = &prev.text[..prev.text.len() - 1];
+            last_end = start;
+        }
+
+        let gap_before = &source[last_end..start];
+        let text = &source[start..span.end];
 
         tokens.push(Token {
             text,
             shape: shape.clone(),
             gap_before,
+            start,
         });
 
         last_end = span.end;
@@ -42,6 +60,7 @@ pub fn preprocess_tokens<'a>(source: &'a str, flattened: &[(Span, FlatShape)]) -
             text: "",
             shape: FlatShape::Nothing,
             gap_before: &source[last_end..],
+            start: source.len(),
         });
     }
 
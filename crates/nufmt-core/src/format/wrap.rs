@@ -0,0 +1,78 @@
+//! Constructs that can only span lines inside parentheses.
+//!
+//! Nushell ends a statement at a line break unless it is inside brackets, so
+//! long conditions and command calls are broken inside `( ... )`, reusing the
+//! parentheses already written around them when there are some.
+
+use nu_protocol::{
+    BlockId,
+    ast::{Expr, Expression},
+    engine::StateWorkingSet,
+};
+
+/// A condition or command call that can be broken into pieces, one per line,
+/// located by byte offsets in the source.
+#[derive(Debug, Clone)]
+pub struct Wrap {
+    /// Offset of the first character, which is the `(` if `parenthesized`.
+    pub start: usize,
+    /// Offset just past the last character.
+    pub end: usize,
+    /// Whether the source already wraps the construct in parentheses.
+    pub parenthesized: bool,
+    /// Whether the first piece stays on the line of the `(`, like the name of
+    /// a command does.
+    pub hug_open: bool,
+    /// Start offsets of the pieces that each go on a line of their own.
+    pub breaks: Vec<usize>,
+    /// Offset where measuring the width of the unbroken line stops.
+    pub measure_end: usize,
+    /// Width of what always follows on the line, like the ` {` of an `if`.
+    pub trailing_width: usize,
+    /// Whether the source already breaks the construct across lines.
+    pub multiline: bool,
+}
+
+impl Wrap {
+    /// Offset of the first piece, which starts a line when broken.
+    pub fn first_piece(&self) -> usize {
+        if self.hug_open {
+            self.start
+        } else {
+            self.start + usize::from(self.parenthesized)
+        }
+    }
+}
+
+/// The expression inside `( ... )`, if `expr` is a subexpression holding a
+/// single one.
+pub fn parenthesized_expression<'a>(
+    working_set: &'a StateWorkingSet,
+    expr: &Expression,
+    source: &str,
+) -> Option<&'a Expression> {
+    let inner = match &expr.expr {
+        Expr::FullCellPath(path) if path.tail.is_empty() => match path.head.expr {
+            Expr::Subexpression(id) => only_expression(working_set, id),
+            _ => None,
+        },
+        Expr::Subexpression(id) => only_expression(working_set, *id),
+        _ => None,
+    }?;
+    let text = source.get(expr.span.start..expr.span.end)?;
+    (text.starts_with('(') && text.ends_with(')')).then_some(inner)
+}
+
+/// The expression of a block holding a single one.
+pub fn only_expression<'a>(
+    working_set: &'a StateWorkingSet,
+    id: BlockId,
+) -> Option<&'a Expression> {
+    match working_set.get_block(id).pipelines.as_slice() {
+        [pipeline] => match pipeline.elements.as_slice() {
+            [element] => Some(&element.expr),
+            _ => None,
+        },
+        _ => None,
+    }
+}
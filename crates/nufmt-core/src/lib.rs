@@ -5,5 +5,9 @@
 mod config;
 mod format;
 
-pub use config::{BracketSpacing, Config, ConfigError, QuoteStyle, TrailingComma};
+pub use config::{
+    BlockLayout, BracketSpacing, CollectionLayout, Config, ConfigError, DigitGrouping,
+    FlagValueStyle, KeyQuotes, NumberCase, PipePosition, QuoteStyle, RedirectionStyle,
+    TrailingComma, UnitCase,
+};
 pub use format::{FormatError, SourceLocation, debug_tokens, format_source};
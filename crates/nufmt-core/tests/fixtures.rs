@@ -12,7 +12,16 @@ fn test_fixture(name: &str) {
     let expected = fs::read_to_string(&expected_path)
         .unwrap_or_else(|e| panic!("Failed to read {}: {e}", expected_path.display()));
 
-    let config = Config::default();
+    // A fixture can set options in `name.toml`, otherwise the defaults apply
+    let config_path = fixtures_dir.join(format!("{name}.toml"));
+    let config: Config = if config_path.exists() {
+        let text = fs::read_to_string(&config_path)
+            .unwrap_or_else(|e| panic!("Failed to read {}: {e}", config_path.display()));
+        toml::from_str(&text)
+            .unwrap_or_else(|e| panic!("Failed to parse {}: {e}", config_path.display()))
+    } else {
+        Config::default()
+    };
     let result = format_source(&input, &config)
         .unwrap_or_else(|e| panic!("Failed to format {}: {e}", input_path.display()));
 
@@ -43,4 +52,19 @@ fixture_tests!(
     comments_only,
     deeply_nested,
     unicode,
+    pipelines,
+    signatures,
+    closures,
+    conditionals,
+    try_catch,
+    match_arms,
+    tables,
+    records,
+    collections,
+    operators,
+    commands,
+    redirections,
+    conditions,
+    calls,
+    block_layout_auto,
 );